@@ -0,0 +1,105 @@
+// Throughput of the sled store with and without a cache in front of it,
+// on a workload where most transactions go to a few clients.
+//
+//     cargo bench --bench cache
+//
+// ROWS sets the number of transactions, 200 000 by default.
+
+use std::env;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use payments::{Accounts, CachedContainer, Container, Durability, TransactionData, DB};
+
+const CLIENTS: u64 = 10_000;
+// nine out of ten transactions go to this many clients
+const HOT: u64 = 10;
+const CACHED: usize = 1000;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let rows = env::var("ROWS")
+        .ok()
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(200_000);
+
+    let csv = generate(rows);
+    let dir = env::temp_dir().join(format!("payments-bench-cache-{}", std::process::id()));
+
+    println!("{} rows, {} clients, {} hot", rows, CLIENTS, HOT);
+    for (name, durability) in [
+        ("every commit", Durability::EveryCommit),
+        ("end of batch", Durability::EndOfBatch),
+    ]
+    .iter()
+    {
+        let db = DB::ephemeral(sled::open(dir.join(format!("plain-{}", name)))?)?
+            .with_durability(*durability);
+        report(&format!("sled, {}", name), rows, run(&csv, db)?);
+
+        let db = DB::ephemeral(sled::open(dir.join(format!("cached-{}", name)))?)?
+            .with_durability(*durability);
+        let cached = CachedContainer::new(db, CACHED);
+        report(&format!("cached sled, {}", name), rows, run(&csv, cached)?);
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn report(name: &str, rows: u64, elapsed: Duration) {
+    println!(
+        "{:>26}: {:>10.0} rows/s ({:.2?})",
+        name,
+        rows as f64 / elapsed.as_secs_f64(),
+        elapsed
+    );
+}
+
+fn run<T: Container>(csv: &str, db: T) -> Result<Duration, Box<dyn Error>> {
+    let mut r = csv::ReaderBuilder::default()
+        .trim(csv::Trim::All)
+        .from_reader(csv.as_bytes());
+    let mut accounts = Accounts::new(db);
+
+    let start = Instant::now();
+    for td in r.deserialize::<TransactionData>() {
+        let _ = accounts.process(td?);
+    }
+    accounts.flush()?;
+    Ok(start.elapsed())
+}
+
+// Deposits and withdrawals, most of them for the hot clients
+fn generate(rows: u64) -> String {
+    let mut csv = String::from("type, client, tx, amount\n");
+
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    for tx in 1..=rows {
+        let client = if next() % 10 == 0 {
+            next() % CLIENTS + 1
+        } else {
+            next() % HOT + 1
+        };
+        let t_type = if next() % 10 < 7 {
+            "deposit"
+        } else {
+            "withdrawal"
+        };
+        csv.push_str(&format!(
+            "{}, {}, {}, {}.{:04}\n",
+            t_type,
+            client,
+            tx,
+            next() % 100,
+            next() % 10000
+        ));
+    }
+    csv
+}
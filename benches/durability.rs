@@ -0,0 +1,120 @@
+// Throughput of every durability level on the same synthetic csv.
+//
+//     cargo bench --bench durability
+//
+// ROWS sets the size of the csv, a million rows by default.
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use payments::{Accounts, Durability, TransactionData, DB};
+
+const CLIENTS: u64 = 1000;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let rows = env::var("ROWS")
+        .ok()
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(1_000_000);
+
+    let dir = env::temp_dir().join(format!("payments-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let csv = dir.join("transactions.csv");
+    generate(&csv, rows)?;
+
+    let levels = [
+        ("every commit", Durability::EveryCommit),
+        (
+            "group of 1000 or 10ms",
+            Durability::Group {
+                commits: 1000,
+                interval: Duration::from_millis(10),
+            },
+        ),
+        ("end of batch", Durability::EndOfBatch),
+    ];
+
+    println!("{} rows", rows);
+    for (i, (name, durability)) in levels.iter().enumerate() {
+        let db = sled::open(dir.join(format!("db-{}", i)))?;
+        let db = DB::ephemeral(db)?.with_durability(*durability);
+
+        let elapsed = run(&csv, db)?;
+        println!(
+            "{:>24}: {:>10.0} rows/s ({:.2?})",
+            name,
+            rows as f64 / elapsed.as_secs_f64(),
+            elapsed
+        );
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn run(csv: &Path, db: DB) -> Result<Duration, Box<dyn Error>> {
+    let mut r = csv::ReaderBuilder::default()
+        .trim(csv::Trim::All)
+        .from_path(csv)?;
+    let mut accounts = Accounts::new(db);
+
+    let start = Instant::now();
+    for td in r.deserialize::<TransactionData>() {
+        // rejected rows cost the same to read, they are part of the load
+        let _ = accounts.process(td?);
+    }
+    accounts.flush()?;
+    Ok(start.elapsed())
+}
+
+// Mostly deposits and withdrawals with a dispute now and then,
+// most of which get resolved.
+fn generate(path: &Path, rows: u64) -> Result<(), Box<dyn Error>> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "type, client, tx, amount")?;
+
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    for tx in 1..=rows {
+        let client = tx % CLIENTS + 1;
+        match next() % 100 {
+            0..=59 => writeln!(
+                w,
+                "deposit, {}, {}, {}.{:04}",
+                client,
+                tx,
+                next() % 1000,
+                next() % 10000
+            )?,
+            60..=94 => writeln!(
+                w,
+                "withdrawal, {}, {}, {}.{:04}",
+                client,
+                tx,
+                next() % 100,
+                next() % 10000
+            )?,
+            // the deposits of the client are CLIENTS apart
+            95..=97 => writeln!(w, "dispute, {}, {}, ", client, tx.saturating_sub(CLIENTS))?,
+            _ => writeln!(
+                w,
+                "resolve, {}, {}, ",
+                client,
+                tx.saturating_sub(2 * CLIENTS)
+            )?,
+        }
+    }
+
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use payments::{Archive, Durability, NegativeBalance};
+
+pub enum Store {
+    Sled,
+    Sqlite,
+    Memory,
+}
+
+pub struct Config {
+    pub path: String,
+    // where a persistent store lives
+    // from --db, PAYMENTS_DB or the config file, in that order
+    // setting it makes the run persistent
+    pub db: Option<String>,
+    // keep the balances in the database between runs
+    pub persistent: bool,
+    pub store: Store,
+    // when the storage fails we either skip the transaction
+    // or stop processing altogether
+    pub continue_on_storage_error: bool,
+    // where to report the rows that were not applied
+    pub rejects: Option<String>,
+    // stop at the first row that cannot be applied
+    pub strict: bool,
+    // when the database flushes the committed transactions
+    pub durability: Durability,
+    // a snapshot to load into the empty store before processing
+    pub import: Option<String>,
+    // where to write a snapshot of the store after processing
+    pub export: Option<String>,
+    // upgrade every stored record to the current schema before processing
+    pub migrate: bool,
+    // how many accounts to keep in a write-back cache in front of the store
+    pub cache: Option<usize>,
+    // which settled transactions to archive after processing
+    pub archive: Option<Archive>,
+    // whether disputes and chargebacks can leave an account in debt
+    pub negative_balance: NegativeBalance,
+    // whether the input can unlock, freeze, suspend and close accounts
+    pub admin: bool,
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        let mut args = env::args().skip(1);
+        let mut path = None;
+        let mut persistent = false;
+        let mut store = Store::Sled;
+        let mut continue_on_storage_error = false;
+        let mut rejects = None;
+        let mut strict = false;
+        let mut durability = Durability::EveryCommit;
+        let mut import = None;
+        let mut export = None;
+        let mut migrate = false;
+        let mut cache = None;
+        let mut archive = None;
+        let mut negative_balance = NegativeBalance::Refuse;
+        let mut admin = false;
+        let mut db = None;
+        let mut config = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--persistent" => persistent = true,
+                "--strict" => strict = true,
+                "--migrate" => migrate = true,
+                "--allow-negative" => negative_balance = NegativeBalance::Allow,
+                "--allow-admin" => admin = true,
+                "--store" => {
+                    store = match args.next().as_deref() {
+                        Some("sled") => Store::Sled,
+                        Some("sqlite") => Store::Sqlite,
+                        Some("memory") => Store::Memory,
+                        other => {
+                            return Err(format!(
+                                "unknown store {:?}, expected sled, sqlite or memory",
+                                other.unwrap_or_default()
+                            ))
+                        }
+                    }
+                }
+                "--on-storage-error" => {
+                    continue_on_storage_error = match args.next().as_deref() {
+                        Some("abort") => false,
+                        Some("continue") => true,
+                        other => {
+                            return Err(format!(
+                                "unknown storage error setting {:?}, expected abort or continue",
+                                other.unwrap_or_default()
+                            ))
+                        }
+                    }
+                }
+                "--durability" => {
+                    let arg = args.next().unwrap_or_default();
+                    durability = parse_durability(&arg).ok_or_else(|| {
+                        format!(
+                            "unknown durability {:?}, expected commit, batch or group:<commits>:<ms>",
+                            arg
+                        )
+                    })?;
+                }
+                "--db" => {
+                    db = Some(args.next().ok_or("--db requires a path")?);
+                }
+                "--config" => {
+                    config = Some(args.next().ok_or("--config requires a file path")?);
+                }
+                "--cache" => {
+                    let arg = args.next().unwrap_or_default();
+                    let capacity = arg.parse().map_err(|_| {
+                        format!("--cache requires a number of accounts, got {:?}", arg)
+                    })?;
+                    cache = Some(capacity);
+                }
+                "--archive" => {
+                    let arg = args.next().unwrap_or_default();
+                    archive = Some(parse_archive(&arg).ok_or_else(|| {
+                        format!(
+                            "unknown archive policy {:?}, expected after:<n> or keep:<n>",
+                            arg
+                        )
+                    })?);
+                }
+                "--import" => {
+                    import = Some(args.next().ok_or("--import requires a file path")?);
+                }
+                "--export" => {
+                    export = Some(args.next().ok_or("--export requires a file path")?);
+                }
+                "--rejects" => {
+                    rejects = Some(args.next().ok_or("--rejects requires a file path")?);
+                }
+                _ => path = Some(arg),
+            }
+        }
+
+        let path = path.ok_or("filepath to a csv file is required as an argument")?;
+
+        let db = match db {
+            Some(db) => Some(db),
+            None => match env::var("PAYMENTS_DB") {
+                Ok(db) if !db.is_empty() => Some(db),
+                _ => match config {
+                    Some(config) => {
+                        let contents = fs::read_to_string(&config)
+                            .map_err(|e| format!("cannot read {}: {}", config, e))?;
+                        parse_config(&contents).map_err(|e| format!("{}: {}", config, e))?
+                    }
+                    None => None,
+                },
+            },
+        };
+
+        // a store that is given a place is kept there
+        let persistent = persistent || db.is_some();
+        if persistent {
+            if let Store::Memory = store {
+                return Err(match &db {
+                    Some(db) => format!("the memory store cannot be kept at {}", db),
+                    None => "the memory store cannot be persistent".into(),
+                });
+            }
+        }
+
+        Ok(Self {
+            path,
+            db,
+            persistent,
+            store,
+            continue_on_storage_error,
+            rejects,
+            strict,
+            durability,
+            import,
+            export,
+            migrate,
+            cache,
+            archive,
+            negative_balance,
+            admin,
+        })
+    }
+}
+
+// key = value lines, blank lines and lines starting with # are skipped
+// db is the only key so far, it is returned if it is set
+fn parse_config(contents: &str) -> Result<Option<String>, String> {
+    let mut db = None;
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value", n + 1))?;
+        match key.trim() {
+            "db" => db = Some(value.trim().to_string()),
+            other => return Err(format!("line {}: unknown key {:?}", n + 1, other)),
+        }
+    }
+    Ok(db)
+}
+
+// commit, batch or group:<commits>:<ms>
+fn parse_durability(s: &str) -> Option<Durability> {
+    match s {
+        "commit" => return Some(Durability::EveryCommit),
+        "batch" => return Some(Durability::EndOfBatch),
+        _ => {}
+    }
+
+    let mut parts = s.strip_prefix("group:")?.splitn(2, ':');
+    let commits = parts.next()?.parse().ok()?;
+    let ms = parts.next()?.parse().ok()?;
+    Some(Durability::Group {
+        commits,
+        interval: Duration::from_millis(ms),
+    })
+}
+
+// after:<transactions> or keep:<transactions>
+fn parse_archive(s: &str) -> Option<Archive> {
+    let (policy, n) = s.split_once(':')?;
+    match policy {
+        "after" => n.parse().ok().map(Archive::After),
+        "keep" => n.parse().ok().map(Archive::Keep),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_file() {
+        let contents = "
+            # where the balances are kept
+            db = /var/lib/payments
+
+        ";
+        assert_eq!(
+            parse_config(contents),
+            Ok(Some("/var/lib/payments".to_string()))
+        );
+        assert_eq!(parse_config("# nothing set\n"), Ok(None));
+        assert!(parse_config("db /var/lib/payments").is_err());
+        assert!(parse_config("store = sled").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::{error, fmt};
+
+use payments::{ActionError, InnerError, ProcessError, SnapshotError};
+
+// Why a row of the input was not applied
+// or why the run could not go on at all
+pub enum Failure {
+    // the row is not a valid csv record
+    Parse(csv::Error),
+    // the row does not make a valid transaction
+    Validation(InnerError),
+    // the transaction could not be applied to the account
+    Apply(ActionError),
+    // the snapshot could not be imported or exported
+    Snapshot(SnapshotError),
+    // another run holds the store at this path
+    InUse(String),
+    // the stored records could not be brought up to the current schema
+    Migrate(ActionError),
+}
+
+impl Failure {
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Failure::Parse(_) => "parse",
+            Failure::Validation(_) => "validation",
+            Failure::Apply(_) => "apply",
+            Failure::Snapshot(_) => "snapshot",
+            Failure::InUse(_) => "open",
+            Failure::Migrate(_) => "migrate",
+        }
+    }
+
+    pub fn error(&self) -> &'static str {
+        match self {
+            Failure::Parse(e) => match e.kind() {
+                csv::ErrorKind::Io(_) => "Io",
+                csv::ErrorKind::Utf8 { .. } => "Utf8",
+                csv::ErrorKind::Deserialize { err, .. }
+                    if matches!(err.kind(), csv::DeserializeErrorKind::InvalidUtf8(_)) =>
+                {
+                    "Utf8"
+                }
+                csv::ErrorKind::UnequalLengths { .. } => "UnequalLengths",
+                csv::ErrorKind::Deserialize { .. } => "Deserialize",
+                _ => "Csv",
+            },
+            Failure::Validation(e) => e.kind(),
+            Failure::Apply(e) | Failure::Migrate(e) => e.kind(),
+            Failure::Snapshot(_) => "Snapshot",
+            Failure::InUse(_) => "InUse",
+        }
+    }
+
+    // Every class of failure exits with its own code
+    // so the callers can tell them apart, see the README
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Parse(_) => 2,
+            Failure::Validation(InnerError::Unauthorized(_)) => 12,
+            Failure::Validation(_) => 3,
+            Failure::Apply(e) if e.is_storage() => 8,
+            Failure::Apply(ActionError::AccountLocked) => 4,
+            Failure::Apply(ActionError::InsufficientFunds) => 5,
+            Failure::Apply(ActionError::InvalidClientID) => 6,
+            Failure::Apply(ActionError::InvalidTxID) => 7,
+            Failure::Apply(ActionError::ClientMismatch) => 10,
+            Failure::Apply(ActionError::InDebt) => 11,
+            Failure::Apply(ActionError::OpenDisputes) => 13,
+            Failure::Apply(ActionError::PayoutMismatch) => 17,
+            Failure::Apply(_) => 9,
+            Failure::Snapshot(_) => 14,
+            Failure::InUse(_) => 15,
+            Failure::Migrate(_) => 16,
+        }
+    }
+
+    pub fn reason(&self) -> String {
+        match self {
+            Failure::Parse(e) => e.to_string(),
+            Failure::Validation(e) => e.to_string(),
+            Failure::Apply(e) | Failure::Migrate(e) => e.to_string(),
+            Failure::Snapshot(e) => e.to_string(),
+            Failure::InUse(path) => format!("the store at {} is in use by another run", path),
+        }
+    }
+}
+
+impl From<ProcessError> for Failure {
+    fn from(e: ProcessError) -> Self {
+        match e {
+            ProcessError::Validation(e) => Failure::Validation(e),
+            ProcessError::Apply(e) => Failure::Apply(e),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed, {}: {}",
+            self.stage(),
+            self.error(),
+            self.reason()
+        )
+    }
+}
+
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for Failure {}
@@ -1,96 +1,257 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::process;
-use std::{env, io};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-mod payments;
+mod config;
+mod failure;
+mod rejects;
 
+use config::{Config, Store};
+use failure::Failure;
 use payments::{
-    Accounts, Chargeback, Deposit, Dispute, Resolve, Transaction, TransactionData, TransactionType,
-    Withdrawal, DB,
+    Accounts, CachedContainer, Container, MemoryContainer, SnapshotError, SqliteContainer, DB,
 };
+use rejects::{Rejects, Tape};
 
-use csv::{DeserializeRecordsIter, Writer};
+use csv::Writer;
 
-macro_rules! handle {
-    ($t:ty,$acc:ident,$td:ident) => {
-        let t = match Transaction::<$t>::new($td) {
-            Ok(td) => td,
-            Err(_) => {
-                //println!("{:#?}", e);
-                continue;
-            }
-        };
+const DB_PATH: &str = "./db/";
+const SQLITE_PATH: &str = "./payments.sqlite";
 
-        if let Err(_) = $acc.handle(t) {
-            //println!("{:#?}", e);
+// Why isn't the amount in the smallest divisible unit?
+// It is less error prone and easier to handle
+fn main() {
+    let cfg = match Config::from_args() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
         }
     };
+
+    if let Err(e) = run(&cfg) {
+        eprintln!("aborting: {}", e);
+        let code = e.downcast_ref::<Failure>().map_or(1, Failure::exit_code);
+        process::exit(code);
+    }
 }
 
-const DB_PATH: &str = "./db/";
+fn run(cfg: &Config) -> Result<(), Box<dyn Error>> {
+    match cfg.store {
+        Store::Sled => {
+            // every ephemeral run gets a store of its own
+            // so it cannot wipe the data of another run
+            // the lock is held until the run is over
+            let (db, _lock) = if cfg.persistent {
+                let path = cfg.db.as_deref().unwrap_or(DB_PATH);
+                let lock = lock_store(Path::new(path))?;
+                (DB::new(sled::Config::new().path(path).open()?)?, Some(lock))
+            } else {
+                let path = run_dir();
+                let db = sled::Config::new().path(&path).temporary(true).open()?;
+                (DB::ephemeral(db)?, None)
+            };
+            let mut db = db.with_durability(cfg.durability);
+            if cfg.migrate {
+                let migrated = db.migrate().map_err(Failure::Migrate)?;
+                eprintln!("migrated {} records to the current schema", migrated);
+            }
 
-// Why isn't the amount in the smallest divisible unit?
-// It is less error prone and easier to handle
-fn main() -> csv::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("filepath to a csv file is required as an argument");
-        process::exit(1);
+            run_with(cfg, db)
+        }
+        Store::Sqlite => {
+            let db = if cfg.persistent {
+                let path = cfg.db.as_deref().unwrap_or(SQLITE_PATH);
+                SqliteContainer::open(path).map_err(|e| in_use(e, path))?
+            } else {
+                SqliteContainer::in_memory()?
+            };
+
+            run_with(cfg, db)
+        }
+        Store::Memory => run_with(cfg, MemoryContainer::new()),
     }
+}
+
+// a directory under the system temp dir, unique to this run
+fn run_dir() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    std::env::temp_dir().join(format!("payments-{}-{}", process::id(), nanos))
+}
+
+// sled locks the store while it is open too, but it does not
+// tell a locked store apart from any other failure to open it
+// so a run takes a lock file of its own in the store first
+// File::try_lock needs Rust 1.89, see rust-version in Cargo.toml
+fn lock_store(path: &Path) -> Result<File, Box<dyn Error>> {
+    fs::create_dir_all(path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join("payments.lock"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Failure::InUse(path.display().to_string()).into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+// SQLite reports a store in use as busy
+fn in_use(e: rusqlite::Error, path: &str) -> Box<dyn Error> {
+    match e {
+        rusqlite::Error::SqliteFailure(ref f, _)
+            if f.code == rusqlite::ErrorCode::DatabaseBusy
+                || f.code == rusqlite::ErrorCode::DatabaseLocked =>
+        {
+            Failure::InUse(path.to_string()).into()
+        }
+        e => e.into(),
+    }
+}
 
-    let db = sled::open(DB_PATH).expect("cannot open the database");
-    let accounts = parse_data(&args[1], db);
-    write_data(accounts)
+fn run_with<T: Container>(cfg: &Config, db: T) -> Result<(), Box<dyn Error>> {
+    match cfg.cache {
+        Some(capacity) => {
+            let accounts = parse_data(cfg, CachedContainer::new(db, capacity))?;
+            write_data(cfg, &accounts)
+        }
+        None => {
+            let accounts = parse_data(cfg, db)?;
+            write_data(cfg, &accounts)
+        }
+    }
 }
 
-fn write_data(accts: Accounts<DB>) -> csv::Result<()> {
+fn write_data<T: Container>(cfg: &Config, accts: &Accounts<T>) -> Result<(), Box<dyn Error>> {
     let out = io::stdout();
     let mut w = Writer::from_writer(out.lock());
 
     for acc in accts.iter() {
-        if let Err(_) = w.serialize(acc) {
-            //println!("{:#?}", e);
-        }
+        let acc = match acc {
+            Ok(acc) => acc,
+            Err(e) => {
+                eprintln!("cannot read an account: {}", e);
+                if !cfg.continue_on_storage_error {
+                    return Err(Failure::Apply(e).into());
+                }
+                continue;
+            }
+        };
+
+        w.serialize(acc)?;
     }
 
     w.flush()?;
     Ok(())
 }
 
-fn parse_data(path: &String, db: sled::Db) -> Accounts<DB> {
+fn parse_data<T: Container>(cfg: &Config, db: T) -> Result<Accounts<T>, Box<dyn Error>> {
     let mut r = csv::ReaderBuilder::default()
         .trim(csv::Trim::All)
-        .from_path(path)
-        .expect("all hell broke loose");
+        .from_reader(Tape::new(File::open(&cfg.path)?));
+    let headers = r.byte_headers()?.clone();
+    let to = r.position().byte();
+    r.get_mut().take(0, to);
 
-    let mut accounts = Accounts::new(DB::new(db));
-    let iter: DeserializeRecordsIter<_, TransactionData> = r.deserialize();
+    let mut rejects = Rejects::new(cfg.rejects.as_deref())?;
+    let mut accounts = Accounts::new(db)
+        .with_negative_balance(cfg.negative_balance)
+        .with_admin(cfg.admin);
 
-    for res in iter {
-        let td = match res {
-            Ok(tr) => tr,
-            Err(_) => {
-                //println!("{:#?}", e);
-                continue;
+    if let Some(path) = &cfg.import {
+        File::open(path)
+            .map_err(SnapshotError::Io)
+            .and_then(|f| accounts.import(BufReader::new(f)))
+            .map_err(Failure::Snapshot)?;
+    }
+
+    // the rows are read as bytes so a row that is not valid utf8
+    // or has the wrong number of fields is still reported,
+    // the row itself is taken from the input as it came in
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let from = r.position().byte();
+        let res = r.read_byte_record(&mut record);
+        let to = r.position().byte();
+        let raw = r.get_mut().take(from, to);
+        let (line, res) = match res {
+            Ok(false) => break,
+            Ok(true) => {
+                let line = record.position().map_or(0, |p| p.line());
+                let res = record
+                    .deserialize(Some(&headers))
+                    .map_err(Failure::Parse)
+                    .and_then(|td| accounts.process(td).map_err(Failure::from));
+                (line, res)
             }
+            Err(e) => (e.position().map_or(0, |p| p.line()), Err(Failure::Parse(e))),
         };
 
-        match td.tx_type() {
-            TransactionType::Deposit => {
-                handle!(Deposit, accounts, td);
-            }
-            TransactionType::Withdrawal => {
-                handle!(Withdrawal, accounts, td);
-            }
-            TransactionType::Dispute => {
-                handle!(Dispute, accounts, td);
-            }
-            TransactionType::Resolve => {
-                handle!(Resolve, accounts, td);
-            }
-            TransactionType::Chargeback => {
-                handle!(Chargeback, accounts, td);
-            }
+        let failure = match res {
+            Ok(()) => continue,
+            Err(f) => f,
+        };
+        rejects.write(line, &raw, &failure)?;
+
+        // storage failures are always reported
+        // everything else only stops the run in strict mode
+        let is_storage = matches!(&failure, Failure::Apply(e) if e.is_storage());
+        let abort = if is_storage {
+            !cfg.continue_on_storage_error
+        } else {
+            cfg.strict
+        };
+
+        if is_storage || abort {
+            eprintln!("line {}, {}: {}", line, raw, failure);
+        }
+
+        if abort {
+            rejects.flush()?;
+            accounts.flush().map_err(Failure::Apply)?;
+            return Err(failure.into());
         }
     }
-    accounts
+
+    rejects.flush()?;
+    if let Some(policy) = cfg.archive {
+        let archived = accounts.archive(policy).map_err(Failure::Apply)?;
+        eprintln!("archived {} transactions", archived);
+    }
+    accounts.flush().map_err(Failure::Apply)?;
+
+    if let Some(path) = &cfg.export {
+        File::create(path)
+            .map_err(SnapshotError::Io)
+            .and_then(|f| accounts.export(BufWriter::new(f)))
+            .map_err(Failure::Snapshot)?;
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn store_in_use() {
+        let path = run_dir();
+        let lock = lock_store(&path).unwrap();
+
+        let err = lock_store(&path).expect_err("the store is locked");
+        assert!(matches!(
+            err.downcast_ref::<Failure>(),
+            Some(Failure::InUse(_))
+        ));
+
+        drop(lock);
+        assert!(lock_store(&path).is_ok());
+        let _ = fs::remove_dir_all(&path);
+    }
 }
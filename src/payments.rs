@@ -1,6 +1,31 @@
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize, Serializer};
-use sled::Iter;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
+use sled::Transactional;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+mod cache;
+pub mod conformance;
+mod memory;
+mod schema;
+pub mod snapshot;
+mod sqlite;
+
+pub use cache::CachedContainer;
+pub use memory::MemoryContainer;
+pub use snapshot::SnapshotError;
+pub use sqlite::SqliteContainer;
+
+macro_rules! handle {
+    ($t:ty,$acc:ident,$td:ident) => {{
+        let t = Transaction::<$t>::new($td).map_err(ProcessError::Validation)?;
+        $acc.handle(t).map_err(ProcessError::Apply)
+    }};
+}
 
 //in an async web service context
 // this code has to be offloaded to non async threads
@@ -8,6 +33,9 @@ use sled::Iter;
 
 pub struct Accounts<T> {
     db: T,
+    negative: NegativeBalance,
+    // whether process takes administrative operations
+    admin: bool,
 }
 
 impl<T> Accounts<T>
@@ -15,48 +43,201 @@ where
     T: Container,
 {
     pub fn new(db: T) -> Self {
-        Self { db }
+        Self {
+            db,
+            negative: NegativeBalance::default(),
+            admin: false,
+        }
     }
-}
 
-impl Accounts<DB> {
-    pub fn iter(&self) -> AccountsIterator {
-        AccountsIterator {
-            iter: self.db.db.iter(),
-        }
+    pub fn with_negative_balance(mut self, negative: NegativeBalance) -> Self {
+        self.negative = negative;
+        self
+    }
+
+    /// Lets [`Accounts::process`] take administrative operations,
+    /// like [`TransactionType::Freeze`]. They are refused by default,
+    /// the data usually comes from a feed the clients fill.
+    /// [`Accounts::handle`] takes them either way.
+    pub fn with_admin(mut self, allowed: bool) -> Self {
+        self.admin = allowed;
+        self
     }
 }
 
 pub struct DB {
     db: sled::Db,
+    // every transaction keyed by its id
+    txs: sled::Tree,
+    // every applied action keyed by its sequence number
+    // big endian so they iterate in order
+    events: sled::Tree,
+    // the settled transactions moved out of txs, keyed the same way
+    archive: sled::Tree,
+    // the schema version of the store, the count of txs
+    // and how far Accounts::archive went through the log
+    meta: sled::Tree,
+    next_seq: u64,
+    // the transactions in txs
+    hot: u64,
+    clear_on_drop: bool,
+    durability: Durability,
+    // commits since the last flush
+    pending: usize,
+    last_flush: Instant,
+    #[cfg(test)]
+    fail_after_writes: Option<usize>,
+}
+
+/// How often the [`DB`] makes the committed changes durable.
+/// A commit is atomic at every level,
+/// a crash loses whatever was not flushed yet.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability {
+    /// Flush after every commit, the slowest and the safest.
+    #[default]
+    EveryCommit,
+    /// Flush once this many commits piled up
+    /// or once the interval passed since the last flush.
+    Group { commits: usize, interval: Duration },
+    /// Flush only when [`Container::flush`] is called at the end of the batch.
+    EndOfBatch,
+}
+
+/// Which transactions [`Accounts::archive`] moves to the archive.
+///
+/// Only transactions that can no longer change are archived,
+/// an open dispute stays where it is however old it gets.
+/// Ages are counted in transactions, the input carries no time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Archive {
+    /// Everything older than this many transactions.
+    /// The deposits and withdrawals among them can no longer be disputed.
+    After(u64),
+    /// The oldest transactions, until at most this many are left.
+    Keep(usize),
+}
+
+/// Whether a dispute or a chargeback can take more than the client has.
+///
+/// The client may have withdrawn a deposit before disputing it,
+/// then there is nothing left to hold or to take back.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NegativeBalance {
+    /// The dispute or chargeback is refused with [`ActionError::InsufficientFunds`].
+    #[default]
+    Refuse,
+    /// The balances go negative, recording what the client owes.
+    /// The account takes no withdrawals until deposits cover the debt.
+    Allow,
 }
 
 impl DB {
-    pub fn new(db: sled::Db) -> Self {
-        Self { db }
+    // the data outlives the process
+    // the next run continues from the stored accounts
+    pub fn new(db: sled::Db) -> sled::Result<Self> {
+        Self::open(db, false)
     }
-}
 
-impl Drop for DB {
-    fn drop(&mut self) {
-        let _ = self.db.clear();
-        let _ = self.db.flush();
+    // everything is wiped once the DB is dropped
+    // useful for one off runs and tests
+    pub fn ephemeral(db: sled::Db) -> sled::Result<Self> {
+        Self::open(db, true)
+    }
+
+    fn open(db: sled::Db, clear_on_drop: bool) -> sled::Result<Self> {
+        let txs = db.open_tree("transactions")?;
+        let events = db.open_tree("events")?;
+        let archive = db.open_tree("archive")?;
+        let meta = db.open_tree("meta")?;
+        schema::prepare(&db, &txs, &events, &archive, &meta)?;
+        let next_seq = match events.last()? {
+            Some((k, _)) => seq_from_key(&k) + 1,
+            None => 1,
+        };
+        // a store that does not keep the count yet is counted once
+        let hot = match meta.get(HOT_KEY)? {
+            Some(v) => seq_from_key(&v),
+            None => {
+                let hot = txs.len() as u64;
+                meta.insert(HOT_KEY, &hot.to_be_bytes())?;
+                hot
+            }
+        };
+        Ok(Self {
+            db,
+            txs,
+            events,
+            archive,
+            meta,
+            next_seq,
+            hot,
+            clear_on_drop,
+            durability: Durability::default(),
+            pending: 0,
+            last_flush: Instant::now(),
+            #[cfg(test)]
+            fail_after_writes: None,
+        })
+    }
+
+    /// Rewrites every record stored in an older schema version
+    /// in the current one and returns how many there were.
+    /// Old records are upgraded whenever they are read anyway,
+    /// this spares doing it on every read.
+    pub fn migrate(&mut self) -> Result<usize, ActionError> {
+        let migrated = schema::migrate::<Account>(&self.db)?
+            + schema::migrate::<Record>(&self.txs)?
+            + schema::migrate::<Event>(&self.events)?
+            + schema::migrate::<Record>(&self.archive)?;
+        self.flush()?;
+        Ok(migrated)
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
     }
-}
 
-pub struct AccountsIterator {
-    iter: Iter,
+    fn flush_due(&self) -> bool {
+        match self.durability {
+            Durability::EveryCommit => true,
+            Durability::Group { commits, interval } => {
+                self.pending >= commits || self.last_flush.elapsed() >= interval
+            }
+            Durability::EndOfBatch => false,
+        }
+    }
 }
 
-impl Iterator for AccountsIterator {
-    type Item = AccountData;
+impl DB {
+    // lets the tests fail a commit half way through
+    #[cfg(test)]
+    fn fault(&self, writes: usize) -> ConflictableTransactionResult<(), ActionError> {
+        if self.fail_after_writes == Some(writes) {
+            let e = ActionError::storage("injected failure");
+            return Err(sled::transaction::ConflictableTransactionError::Abort(e));
+        }
+        Ok(())
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let res = self.iter.next()?;
-        let (_, bytes) = res.unwrap();
-        let acc: Account = bincode::deserialize(&bytes).expect("all hell broke loose");
-        let acc_data: AccountData = acc.into();
-        Some(acc_data)
+    #[cfg(not(test))]
+    #[inline(always)]
+    fn fault(&self, _writes: usize) -> ConflictableTransactionResult<(), ActionError> {
+        Ok(())
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        if self.clear_on_drop {
+            let _ = self.db.clear();
+            let _ = self.txs.clear();
+            let _ = self.events.clear();
+            let _ = self.archive.clear();
+            let _ = self.meta.clear();
+        }
+        let _ = self.db.flush();
     }
 }
 
@@ -65,49 +246,630 @@ where
     T: Container,
 {
     pub fn handle(&mut self, action: impl Action<T>) -> Result<(), ActionError> {
-        action.apply(&mut self.db)
+        action.apply_with(&mut self.db, self.negative)
+    }
+
+    /// Turns the data into a transaction of its type and applies it.
+    pub fn process(&mut self, td: TransactionData) -> Result<(), ProcessError> {
+        if td.t_type.is_admin() && !self.admin {
+            return Err(ProcessError::Validation(InnerError::Unauthorized(
+                td.t_type,
+            )));
+        }
+        self.apply(td)
+    }
+
+    fn apply(&mut self, td: TransactionData) -> Result<(), ProcessError> {
+        match td.tx_type() {
+            TransactionType::Deposit => handle!(Deposit, self, td),
+            TransactionType::Withdrawal => handle!(Withdrawal, self, td),
+            TransactionType::Dispute => handle!(Dispute, self, td),
+            TransactionType::Resolve => handle!(Resolve, self, td),
+            TransactionType::Chargeback => handle!(Chargeback, self, td),
+            TransactionType::Unlock => handle!(Unlock, self, td),
+            TransactionType::Freeze => handle!(Freeze, self, td),
+            TransactionType::Suspend => handle!(Suspend, self, td),
+            TransactionType::Close => handle!(Close, self, td),
+        }
+    }
+
+    // makes everything handled so far durable
+    pub fn flush(&mut self) -> Result<(), ActionError> {
+        self.db.flush()
+    }
+
+    // every action applied so far, in order
+    pub fn events(&self) -> impl Iterator<Item = Result<Event, ActionError>> + '_ {
+        self.db.events(0)
+    }
+
+    /// Writes everything the container holds to a snapshot.
+    pub fn export(&self, w: impl Write) -> Result<(), SnapshotError> {
+        snapshot::export(&self.db, w)
+    }
+
+    /// Loads a snapshot written by [`Accounts::export`].
+    /// The container has to be empty.
+    pub fn import(&mut self, r: impl Read) -> Result<(), SnapshotError> {
+        snapshot::import(r, &mut self.db)
+    }
+
+    /// Applies every event of the log, in order, on top of these accounts.
+    /// The log can come from any container, replaying into an empty one
+    /// rebuilds the accounts as they were when the log was written.
+    ///
+    /// Every event was applied once already,
+    /// so one being rejected now means the history does not add up.
+    /// The history of accounts that went into debt only adds up
+    /// under [`NegativeBalance::Allow`].
+    /// Returns the number of events replayed.
+    pub fn replay<S: Container>(&mut self, log: &S) -> Result<u64, ReplayError> {
+        let mut replayed = 0;
+        for event in log.events(0) {
+            let event = event.map_err(ReplayError::Read)?;
+            let seq = event.seq;
+            // the administrative operations in the log were allowed in once
+            self.apply(event.data)
+                .map_err(|error| ReplayError::Rejected { seq, error })?;
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    /// Moves the settled transactions the policy picks to the archive,
+    /// out of the way of the transactions that are applied.
+    /// Their ids stay taken and they can still be looked up,
+    /// see [`Accounts::transaction`] and [`Accounts::history`].
+    /// Returns the number of transactions archived.
+    pub fn archive(&mut self, policy: Archive) -> Result<usize, ActionError> {
+        let last = self.db.last_seq()?;
+        let mut mark = self.db.archive_mark()?;
+        if last == mark {
+            return Ok(0);
+        }
+        let mut hot = match policy {
+            Archive::After(_) => 0,
+            Archive::Keep(_) => self.db.transaction_count()?,
+        };
+
+        // the log is walked on from where the last run stopped
+        // and the transactions are moved a batch at a time
+        // an open dispute left behind is reached again by its
+        // resolve or chargeback, further down the log
+        let mut archived = 0;
+        loop {
+            let mut changes = Changeset::new();
+            let mut batch = HashSet::new();
+            let mut done = true;
+            let walked = mark;
+            for event in self.db.events(mark + 1) {
+                let event = event?;
+                let old = match policy {
+                    Archive::After(n) => event.seq.saturating_add(n) <= last,
+                    Archive::Keep(n) => hot > n as u64,
+                };
+                if !old {
+                    break;
+                }
+                if batch.len() == ARCHIVE_BATCH {
+                    done = false;
+                    break;
+                }
+                mark = event.seq;
+
+                let tx = event.data.tx;
+                if batch.contains(&tx) {
+                    continue;
+                }
+                match self.db.transaction(&tx)? {
+                    // archived already or rejected when it came in
+                    None => continue,
+                    // still open
+                    Some(Record::Disputed(_)) => continue,
+                    Some(r) => changes = changes.archive(r),
+                }
+                batch.insert(tx);
+                hot = hot.saturating_sub(1);
+            }
+
+            archived += batch.len();
+            if mark != walked {
+                self.db.commit(changes.archived_to(mark))?;
+            }
+            if done {
+                return Ok(archived);
+            }
+        }
+    }
+
+    /// Looks up a transaction by its id, archived or not.
+    pub fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        match self.db.transaction(tx)? {
+            Some(r) => Ok(Some(r)),
+            None => self.db.archived(tx),
+        }
+    }
+
+    /// Every transaction of the client, the archived ones included,
+    /// in no particular order.
+    pub fn history(
+        &self,
+        client: ClientID,
+    ) -> impl Iterator<Item = Result<Record, ActionError>> + '_ {
+        self.db
+            .transactions()
+            .chain(self.db.archived_transactions())
+            .filter(move |r| match r {
+                Ok(r) => r.client() == client,
+                Err(_) => true,
+            })
+    }
+
+    // works the same for every storage
+    // the accounts come in the order of the client ids
+    pub fn iter(&self) -> impl Iterator<Item = Result<AccountData, ActionError>> + '_ {
+        self.db.accounts().map(|acc| acc.map(AccountData::from))
+    }
+
+    /// The accounts of the clients in the range, like `1000..2000`,
+    /// in the order of the client ids.
+    pub fn range(
+        &self,
+        clients: impl RangeBounds<ClientID>,
+    ) -> impl Iterator<Item = Result<AccountData, ActionError>> + '_ {
+        let clients = (clients.start_bound().cloned(), clients.end_bound().cloned());
+        self.db
+            .accounts_range(clients)
+            .map(|acc| acc.map(AccountData::from))
     }
 }
 
-pub trait Container: private::Sealed {
-    fn get_or_create(&self, id: &ClientID) -> Result<Account, ActionError>;
+/// Storage for the client accounts.
+///
+/// This is the extension point for plugging in your own storage,
+/// anything implementing it can be used with [`Accounts`].
+/// Every method is fallible, failures of the underlying storage
+/// should be reported through [`ActionError::Storage`].
+///
+/// What it stored is put back together with [`Account::from_parts`],
+/// [`TransactionData::from_parts`] and the constructors of the records.
+///
+/// Run [`conformance::run`] against an implementation to check
+/// it behaves the same way as the built in ones.
+pub trait Container {
+    /// Returns the account of the client or a new empty one
+    /// if the client has never been seen before.
+    /// The new account is not stored until it is saved.
+    fn get_or_create(&self, id: &ClientID) -> Result<Account, ActionError> {
+        match self.get_account(id) {
+            Err(ActionError::InvalidClientID) => Ok(Account::new(*id)),
+            res => res,
+        }
+    }
+
+    /// Returns the account of the client
+    /// or [`ActionError::InvalidClientID`] if there is none.
     fn get_account(&self, id: &ClientID) -> Result<Account, ActionError>;
-    fn save_account(&mut self, acc: Account);
+
+    /// Stores the account, replacing the previous version of it.
+    fn save_account(&mut self, acc: Account) -> Result<(), ActionError> {
+        self.commit(Changeset::new().account(acc))
+    }
+
+    /// Returns every stored account, in the order of the client ids.
+    fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_>;
+
+    /// Returns the stored accounts of the clients in the range,
+    /// in the order of the client ids.
+    ///
+    /// The default goes through [`Container::accounts`] up to the end
+    /// of the range, a storage that can seek to the start should.
+    fn accounts_range(
+        &self,
+        clients: (Bound<ClientID>, Bound<ClientID>),
+    ) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        let below = move |acc: &Result<Account, ActionError>| match (acc, clients.0) {
+            (Ok(acc), Bound::Included(start)) => acc.client < start,
+            (Ok(acc), Bound::Excluded(start)) => acc.client <= start,
+            _ => false,
+        };
+        Box::new(
+            self.accounts()
+                .skip_while(below)
+                .take_while(move |acc| match acc {
+                    Ok(acc) => clients.contains(&acc.client),
+                    Err(_) => true,
+                }),
+        )
+    }
+
+    /// Looks up a transaction by its id, across all clients.
+    /// Transaction ids are unique across all clients,
+    /// so this is also how duplicates are detected.
+    ///
+    /// This is called for every action,
+    /// it should not get slower as the history grows.
+    fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError>;
+
+    /// Returns every stored transaction, in no particular order.
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_>;
+
+    /// Looks up a transaction in the archive.
+    ///
+    /// This is called for every new transaction, to keep the ids unique.
+    /// A transaction that is not archived should not be loaded for it.
+    fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError>;
+
+    /// Returns every archived transaction, in no particular order.
+    fn archived_transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_>;
+
+    /// Stores the transaction, replacing the previous state of it.
+    /// Once stored, the id stays taken for good.
+    fn save_transaction(&mut self, record: Record) -> Result<(), ActionError> {
+        self.commit(Changeset::new().record(record))
+    }
+
+    /// Stores every account and transaction of the changeset,
+    /// replacing their previous versions.
+    ///
+    /// This has to be atomic, even across a crash:
+    /// either all of the changes are stored or none of them are.
+    /// The changes have to be readable as soon as this returns,
+    /// they don't have to be durable until [`Container::flush`].
+    ///
+    /// The events of the changeset are appended to the log in order,
+    /// each with a sequence number higher than any before it.
+    ///
+    /// The archived transactions are moved to the archive,
+    /// after the rest of the changeset is stored.
+    fn commit(&mut self, changes: Changeset) -> Result<(), ActionError>;
+
+    /// Returns the logged events in the order they were committed,
+    /// starting at the sequence number `from`.
+    /// The log is append only, events are never changed or removed.
+    fn events(&self, from: u64) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_>;
+
+    /// Returns the sequence number of the last logged event,
+    /// 0 if nothing is logged yet.
+    ///
+    /// The default reads the whole log, a storage that can read it
+    /// from the end should.
+    fn last_seq(&self) -> Result<u64, ActionError> {
+        let mut last = 0;
+        for e in self.events(0) {
+            last = e?.seq;
+        }
+        Ok(last)
+    }
+
+    /// Returns how many transactions are stored, the archived ones aside.
+    ///
+    /// The default counts them one by one, a storage that keeps
+    /// the count should return it instead.
+    fn transaction_count(&self) -> Result<u64, ActionError> {
+        let mut count = 0;
+        for r in self.transactions() {
+            r?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns the sequence number of the last event [`Accounts::archive`]
+    /// went through, as committed with [`Changeset::archived_to`],
+    /// 0 if it never ran.
+    ///
+    /// The default does not keep it, so every run of the archive
+    /// walks the log from the start.
+    fn archive_mark(&self) -> Result<u64, ActionError> {
+        Ok(0)
+    }
+
+    /// Makes every committed change durable.
+    /// Called at the end of a batch.
+    fn flush(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
 }
 
-mod private {
-    pub trait Sealed {}
-    impl<T> Sealed for T where T: super::Container {}
+/// Everything a single action changes.
+/// It is handed to [`Container::commit`] to be stored all at once.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Changeset {
+    accounts: Vec<Account>,
+    records: Vec<Record>,
+    // the actions that made the changes
+    events: Vec<TransactionData>,
+    // moved out of the transactions
+    archived: Vec<Record>,
+    // the last event the archive went through
+    archive_mark: Option<u64>,
 }
 
-impl Container for DB {
-    fn get_or_create(&self, id: &ClientID) -> Result<Account, ActionError> {
-        let acc = match self.get_account(id) {
-            Err(ActionError::InvalidClientID) => Account::new(id.clone()),
-            Ok(k) => k,
-            Err(e) => return Err(e),
-        };
+// how many transactions Accounts::archive moves in one commit
+const ARCHIVE_BATCH: usize = 1000;
+
+impl Changeset {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn account(mut self, acc: Account) -> Self {
+        self.accounts.push(acc);
+        self
+    }
+
+    pub fn record(mut self, record: Record) -> Self {
+        self.records.push(record);
+        self
+    }
+
+    pub fn log(mut self, data: TransactionData) -> Self {
+        self.events.push(data);
+        self
+    }
+
+    pub fn archive(mut self, record: Record) -> Self {
+        self.archived.push(record);
+        self
+    }
+
+    /// Records that [`Accounts::archive`] went through the log up to `seq`.
+    pub fn archived_to(mut self, seq: u64) -> Self {
+        self.archive_mark = Some(seq);
+        self
+    }
+
+    pub fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn events(&self) -> &[TransactionData] {
+        &self.events
+    }
+
+    pub fn archived(&self) -> &[Record] {
+        &self.archived
+    }
 
-        Ok(acc)
+    pub fn archive_mark(&self) -> Option<u64> {
+        self.archive_mark
     }
+}
+
+/// An applied action with its place in the log.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    seq: u64,
+    data: TransactionData,
+}
+
+impl Event {
+    pub fn new(seq: u64, data: TransactionData) -> Self {
+        Self { seq, data }
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn data(&self) -> &TransactionData {
+        &self.data
+    }
+}
+
+// the ids are keyed big endian
+// so the keys sort the same way the ids do
+// the numeric transaction ids go first, tagged 0
+// the opaque ones after them, tagged 1
+fn client_key(id: &ClientID) -> [u8; 8] {
+    id.0.to_be_bytes()
+}
+
+fn tx_key(tx: &TxID) -> Vec<u8> {
+    match &tx.0 {
+        Id::Numeric(id) => {
+            let mut k = vec![0];
+            k.extend_from_slice(&id.to_be_bytes());
+            k
+        }
+        Id::Opaque(id) => {
+            let mut k = vec![1];
+            k.extend_from_slice(id.as_bytes());
+            k
+        }
+    }
+}
+
+// the keys of the counters in the meta tree
+const HOT_KEY: &[u8] = b"hot";
+const MARK_KEY: &[u8] = b"archive_mark";
+
+fn seq_from_key(k: &[u8]) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(k);
+    u64::from_be_bytes(b)
+}
 
+impl Container for DB {
     fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
         let bytes = self
             .db
-            .get(id.to_le_bytes())
-            .unwrap()
+            .get(client_key(id))
+            .map_err(ActionError::storage)?
             .ok_or(ActionError::InvalidClientID)?;
 
-        let acc: Account = bincode::deserialize(&bytes).expect("all hell broke loose");
-        Ok(acc)
+        schema::decode(&bytes)
     }
 
-    fn save_account(&mut self, acc: Account) {
-        let bytes = bincode::serialize(&acc).expect("all hell broke loose");
-        self.db
-            .insert(acc.client.to_le_bytes(), bytes)
-            .expect("all hell broke loose");
-        let _ = self.db.flush();
+    fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        Box::new(self.db.iter().map(|res| {
+            let (_, bytes) = res.map_err(ActionError::storage)?;
+            schema::decode(&bytes)
+        }))
+    }
+
+    fn accounts_range(
+        &self,
+        clients: (Bound<ClientID>, Bound<ClientID>),
+    ) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        let keys = (
+            clients.0.map(|id| client_key(&id)),
+            clients.1.map(|id| client_key(&id)),
+        );
+        Box::new(self.db.range(keys).map(|res| {
+            let (_, bytes) = res.map_err(ActionError::storage)?;
+            schema::decode(&bytes)
+        }))
+    }
+
+    fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        match self.txs.get(tx_key(tx)).map_err(ActionError::storage)? {
+            Some(bytes) => schema::decode(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        Box::new(self.txs.iter().map(|res| {
+            let (_, bytes) = res.map_err(ActionError::storage)?;
+            schema::decode(&bytes)
+        }))
+    }
+
+    fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        match self.archive.get(tx_key(tx)).map_err(ActionError::storage)? {
+            Some(bytes) => schema::decode(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn archived_transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        Box::new(self.archive.iter().map(|res| {
+            let (_, bytes) = res.map_err(ActionError::storage)?;
+            schema::decode(&bytes)
+        }))
+    }
+
+    fn commit(&mut self, changes: Changeset) -> Result<(), ActionError> {
+        // everything is serialized up front
+        // so the transaction itself only has to write
+        let accounts = changes
+            .accounts
+            .iter()
+            .map(|acc| Ok((client_key(&acc.client), schema::encode(acc)?)))
+            .collect::<bincode::Result<Vec<_>>>()
+            .map_err(ActionError::storage)?;
+        let records = changes
+            .records
+            .iter()
+            .map(|r| Ok((tx_key(r.tx()), schema::encode(r)?)))
+            .collect::<bincode::Result<Vec<_>>>()
+            .map_err(ActionError::storage)?;
+        let next_seq = self.next_seq;
+        let events = changes
+            .events
+            .into_iter()
+            .zip(next_seq..)
+            .map(|(data, seq)| Ok((seq.to_be_bytes(), schema::encode(&Event::new(seq, data))?)))
+            .collect::<bincode::Result<Vec<_>>>()
+            .map_err(ActionError::storage)?;
+        let archived = changes
+            .archived
+            .iter()
+            .map(|r| Ok((tx_key(r.tx()), schema::encode(r)?)))
+            .collect::<bincode::Result<Vec<_>>>()
+            .map_err(ActionError::storage)?;
+
+        let mark = changes.archive_mark.map(u64::to_be_bytes);
+
+        let accounts_tree: &sled::Tree = &self.db;
+        let hot = (
+            accounts_tree,
+            &self.txs,
+            &self.events,
+            &self.archive,
+            &self.meta,
+        )
+            .transaction(|(db, txs, log, archive, meta)| {
+                let mut hot = self.hot;
+                let mut writes = 0;
+                for (k, v) in accounts.iter() {
+                    db.insert(&k[..], &v[..])?;
+                    writes += 1;
+                    self.fault(writes)?;
+                }
+                for (k, v) in records.iter() {
+                    if txs.insert(&k[..], &v[..])?.is_none() {
+                        hot += 1;
+                    }
+                    writes += 1;
+                    self.fault(writes)?;
+                }
+                for (k, v) in events.iter() {
+                    log.insert(&k[..], &v[..])?;
+                    writes += 1;
+                    self.fault(writes)?;
+                }
+                for (k, v) in archived.iter() {
+                    if txs.remove(&k[..])?.is_some() {
+                        hot -= 1;
+                    }
+                    archive.insert(&k[..], &v[..])?;
+                    writes += 1;
+                    self.fault(writes)?;
+                }
+                if hot != self.hot {
+                    meta.insert(HOT_KEY, &hot.to_be_bytes())?;
+                }
+                if let Some(mark) = mark {
+                    meta.insert(MARK_KEY, &mark)?;
+                }
+                Ok(hot)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => ActionError::storage(e),
+            })?;
+
+        self.hot = hot;
+        self.next_seq += events.len() as u64;
+        self.pending += 1;
+        if self.flush_due() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn events(&self, from: u64) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_> {
+        Box::new(self.events.range(from.to_be_bytes()..).map(|res| {
+            let (_, bytes) = res.map_err(ActionError::storage)?;
+            schema::decode(&bytes)
+        }))
+    }
+
+    fn last_seq(&self) -> Result<u64, ActionError> {
+        Ok(self.next_seq - 1)
+    }
+
+    fn transaction_count(&self) -> Result<u64, ActionError> {
+        Ok(self.hot)
+    }
+
+    fn archive_mark(&self) -> Result<u64, ActionError> {
+        let mark = self.meta.get(MARK_KEY).map_err(ActionError::storage)?;
+        Ok(mark.map_or(0, |m| seq_from_key(&m)))
+    }
+
+    fn flush(&mut self) -> Result<(), ActionError> {
+        self.db.flush().map_err(ActionError::storage)?;
+        self.pending = 0;
+        self.last_flush = Instant::now();
+        Ok(())
     }
 }
 
@@ -129,7 +891,11 @@ pub struct AccountData {
     held: Decimal,
     #[serde(serialize_with = "round_serialize")]
     total: Decimal,
+    // anything but active
     locked: bool,
+    state: State,
+    // a dispute or chargeback took more than the client had
+    in_debt: bool,
 }
 
 impl From<Account> for AccountData {
@@ -139,7 +905,9 @@ impl From<Account> for AccountData {
             available: acc.available.round_dp(4),
             held: acc.held.round_dp(4),
             total: acc.total.round_dp(4),
-            locked: acc.locked,
+            locked: acc.locked(),
+            state: acc.state(),
+            in_debt: acc.in_debt(),
         }
     }
 }
@@ -147,22 +915,17 @@ impl From<Account> for AccountData {
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Account {
     client: ClientID,
-    #[serde(serialize_with = "round_serialize")]
+    // stored as they are, only the output is rounded, see AccountData
     available: Decimal,
     //we don't need both fields
     //we can calculate one of those values on the fly
     //however, i cannot make that call now as i am not familiar with the exact performance requirements
     // and therefore if the tradeoff with having an extra calculation or using more memory is worth it
-    #[serde(serialize_with = "round_serialize")]
     held: Decimal,
-    #[serde(serialize_with = "round_serialize")]
     total: Decimal,
-    locked: bool,
-
-    deposits: Vec<Transaction<Deposit>>,
-    withdrawals: Vec<Transaction<Withdrawal>>,
-    disputes: Vec<Disputed>,
-    resolves: Vec<Resolved>,
+    status: Status,
+    // the transactions are kept apart from the balances
+    // so the account stays small however long the history gets
 }
 
 fn round_serialize<S>(x: &Decimal, s: S) -> Result<S::Ok, S::Error>
@@ -176,19 +939,154 @@ where
 }
 
 impl Account {
+    /// Puts an account together from what a [`Container`] stored of it.
+    /// Nothing is checked, the parts are taken as they are.
+    pub fn from_parts(
+        client: ClientID,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+        status: Status,
+    ) -> Self {
+        Self {
+            client,
+            available,
+            held,
+            total,
+            status,
+        }
+    }
+
+    pub fn client(&self) -> ClientID {
+        self.client
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    /// The account takes less than an active one does, see [`State::accepts`].
+    pub fn locked(&self) -> bool {
+        self.status != Status::Active
+    }
+
+    pub fn state(&self) -> State {
+        self.status.state()
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// A dispute or a chargeback took more than the client had,
+    /// see [`NegativeBalance::Allow`].
+    pub fn in_debt(&self) -> bool {
+        self.available < Decimal::from(0)
+    }
+
     fn new(cid: ClientID) -> Self {
         Self {
             client: cid,
             available: Decimal::from(0),
             held: Decimal::from(0),
             total: Decimal::from(0),
-            locked: false,
-            deposits: vec![],
-            withdrawals: vec![],
-            disputes: vec![],
-            resolves: vec![],
+            status: Status::Active,
+        }
+    }
+}
+
+/// Where an account is in its lifecycle and what put it there.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum Status {
+    Active,
+    Frozen(Cause),
+    Suspended(Cause),
+    Closed(Cause),
+}
+
+impl Status {
+    pub fn state(&self) -> State {
+        match self {
+            Status::Active => State::Active,
+            Status::Frozen(_) => State::Frozen,
+            Status::Suspended(_) => State::Suspended,
+            Status::Closed(_) => State::Closed,
+        }
+    }
+
+    // an active account has nothing to explain
+    pub fn cause(&self) -> Option<&Cause> {
+        match self {
+            Status::Active => None,
+            Status::Frozen(c) | Status::Suspended(c) | Status::Closed(c) => Some(c),
+        }
+    }
+}
+
+/// The lifecycle states of an account, see [`Status`] for what caused them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Takes every transaction.
+    Active,
+    /// No funds move, the open disputes can still be settled.
+    /// A chargeback freezes the account.
+    Frozen,
+    /// Takes everything but withdrawals.
+    Suspended,
+    /// Takes nothing anymore, not even administrative operations.
+    Closed,
+}
+
+impl State {
+    /// Whether an account in this state takes transactions of the type.
+    pub fn accepts(self, t: TransactionType) -> bool {
+        match self {
+            State::Active => true,
+            State::Frozen => {
+                t.is_admin() || matches!(t, TransactionType::Resolve | TransactionType::Chargeback)
+            }
+            State::Suspended => t != TransactionType::Withdrawal,
+            State::Closed => false,
+        }
+    }
+}
+
+/// Why an account left the active state.
+///
+/// The operator who ordered an administrative operation is not kept
+/// here, only in the event log, with the event of [`Cause::tx`].
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Cause {
+    reason: String,
+    // unknown for the accounts locked before the causes were kept
+    tx: Option<TxID>,
+}
+
+impl Cause {
+    pub fn new(reason: impl Into<String>, tx: Option<TxID>) -> Self {
+        Self {
+            reason: reason.into(),
+            tx,
         }
     }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// The transaction that changed the state.
+    pub fn tx(&self) -> Option<&TxID> {
+        self.tx.as_ref()
+    }
 }
 
 // prevents users on writing exhaustive code
@@ -198,21 +1096,134 @@ impl Account {
 pub enum ActionError {
     AccountLocked,
     InsufficientFunds,
+    // the account owes more than it holds, it takes no withdrawals
+    InDebt,
+    // funds are held for open disputes, the account cannot be closed
+    OpenDisputes,
+    // a close names another payout than what is available
+    PayoutMismatch,
     InvalidClientID,
     InvalidTxID,
+    // the transaction belongs to another client
+    ClientMismatch,
+    // the underlying storage failed
+    Storage(StorageError),
+    // a stored record cannot be read back
+    Corrupted(StorageError),
 }
 
-pub trait Action<T>
-where
-    T: Container,
-{
-    // we consume the action
-    // we don't want the possibility
-    // that it could be executed twice
-    fn apply(self, accts: &mut T) -> Result<(), ActionError>;
+impl ActionError {
+    pub fn storage(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        ActionError::Storage(StorageError::new(e))
+    }
+
+    pub fn corrupted(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        ActionError::Corrupted(StorageError::new(e))
+    }
+
+    // the name of the variant, stable enough to be used in reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ActionError::AccountLocked => "AccountLocked",
+            ActionError::InsufficientFunds => "InsufficientFunds",
+            ActionError::InDebt => "InDebt",
+            ActionError::OpenDisputes => "OpenDisputes",
+            ActionError::PayoutMismatch => "PayoutMismatch",
+            ActionError::InvalidClientID => "InvalidClientID",
+            ActionError::InvalidTxID => "InvalidTxID",
+            ActionError::ClientMismatch => "ClientMismatch",
+            ActionError::Storage(_) => "Storage",
+            ActionError::Corrupted(_) => "Corrupted",
+        }
+    }
+
+    /// The storage failed, rather than the action being invalid.
+    pub fn is_storage(&self) -> bool {
+        matches!(self, ActionError::Storage(_) | ActionError::Corrupted(_))
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::AccountLocked => write!(f, "the account is locked"),
+            ActionError::InsufficientFunds => write!(f, "insufficient funds"),
+            ActionError::InDebt => write!(f, "the account is in debt"),
+            ActionError::OpenDisputes => write!(f, "the account has open disputes"),
+            ActionError::PayoutMismatch => write!(f, "the payout is not what is available"),
+            ActionError::InvalidClientID => write!(f, "unknown client"),
+            ActionError::InvalidTxID => write!(f, "invalid transaction id"),
+            ActionError::ClientMismatch => write!(f, "the transaction belongs to another client"),
+            ActionError::Storage(e) => write!(f, "storage failure: {}", e),
+            ActionError::Corrupted(e) => write!(f, "corrupted record: {}", e),
+        }
+    }
+}
+
+impl error::Error for ActionError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ActionError::Storage(e) | ActionError::Corrupted(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A failure of the storage behind a [`Container`].
+/// It carries the original error of the backend.
+#[derive(Debug)]
+pub struct StorageError {
+    source: Box<dyn error::Error + Send + Sync>,
+}
+
+impl StorageError {
+    pub fn new(source: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        Self {
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+// the boxed errors cannot be compared
+// so we compare what they have to say
+impl PartialEq for StorageError {
+    fn eq(&self, other: &Self) -> bool {
+        self.source.to_string() == other.source.to_string()
+    }
+}
+
+pub trait Action<T>
+where
+    T: Container,
+{
+    // we consume the action
+    // we don't want the possibility
+    // that it could be executed twice
+    fn apply(self, accts: &mut T) -> Result<(), ActionError>;
+
+    // the same under a negative balance policy
+    // only the actions that take funds away care about it
+    fn apply_with(self, accts: &mut T, _negative: NegativeBalance) -> Result<(), ActionError>
+    where
+        Self: Sized,
+    {
+        self.apply(accts)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -220,10 +1231,205 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    // administrative operations, ordered by an operator
+    // the client feed cannot run them, see Accounts::with_admin
+    Unlock,
+    Freeze,
+    Suspend,
+    Close,
+}
+
+impl TransactionType {
+    pub fn is_admin(self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock
+                | TransactionType::Freeze
+                | TransactionType::Suspend
+                | TransactionType::Close
+        )
+    }
+}
+
+/// Identifies a client, any number that fits in 64 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClientID(u64);
+
+impl ClientID {
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for ClientID {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for ClientID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Identifies a transaction, unique across all clients.
+///
+/// An id of digits only is a number that fits in 64 bits, written without a leading zero.
+/// Anything else, like a UUID, is an opaque id of at most [`TxID::MAX_LEN`]
+/// visible ASCII characters, kept as it is.
+/// Parse one with [`str::parse`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TxID(Id);
+
+// the numbers sort before the opaque ids
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+enum Id {
+    Numeric(u64),
+    Opaque(String),
+}
+
+impl TxID {
+    pub const MAX_LEN: usize = 64;
+
+    pub const fn numeric(id: u64) -> Self {
+        Self(Id::Numeric(id))
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.0 {
+            Id::Numeric(id) => Some(id),
+            Id::Opaque(_) => None,
+        }
+    }
+
+    pub fn as_opaque(&self) -> Option<&str> {
+        match &self.0 {
+            Id::Numeric(_) => None,
+            Id::Opaque(id) => Some(id),
+        }
+    }
+}
+
+impl From<u64> for TxID {
+    fn from(id: u64) -> Self {
+        Self::numeric(id)
+    }
+}
+
+impl std::str::FromStr for TxID {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, IdError> {
+        if s.is_empty() {
+            return Err(IdError::Empty);
+        }
+        // digits are always a number, written one way only
+        // so 0123 cannot be taken for 123 and nothing is ever truncated
+        if s.bytes().all(|b| b.is_ascii_digit()) {
+            if s.len() > 1 && s.starts_with('0') {
+                return Err(IdError::LeadingZero);
+            }
+            return s.parse().map(Self::numeric).map_err(|_| IdError::Overflow);
+        }
+        if s.len() > Self::MAX_LEN {
+            return Err(IdError::TooLong(s.len()));
+        }
+        if let Some(c) = s.chars().find(|c| !c.is_ascii_graphic()) {
+            return Err(IdError::Invalid(c));
+        }
+        Ok(Self(Id::Opaque(s.to_string())))
+    }
+}
+
+impl fmt::Display for TxID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Id::Numeric(id) => id.fmt(f),
+            Id::Opaque(id) => id.fmt(f),
+        }
+    }
+}
+
+// written as it came in to csv, as the variant to the stores
+impl Serialize for TxID {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.collect_str(self)
+        } else {
+            self.0.serialize(s)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TxID {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        if !d.is_human_readable() {
+            return Id::deserialize(d).map(Self);
+        }
+
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = TxID;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a transaction id")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<TxID, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<TxID, E> {
+                Ok(TxID::numeric(v))
+            }
+        }
+
+        d.deserialize_str(Visitor)
+    }
+}
+
+/// Why a transaction id is not valid.
+// prevents users on writing exhaustive code
+// so their code won't break when/if we add new variants
+#[non_exhaustive]
+#[derive(Debug, PartialEq)]
+pub enum IdError {
+    Empty,
+    // digits beyond u64::MAX
+    Overflow,
+    // digits that start with a 0, the number is written without it
+    LeadingZero,
+    // an opaque id longer than TxID::MAX_LEN
+    TooLong(usize),
+    // an opaque id with a character that is not visible ASCII
+    Invalid(char),
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdError::Empty => write!(f, "the transaction id is empty"),
+            IdError::Overflow => write!(f, "the transaction id does not fit in 64 bits"),
+            IdError::LeadingZero => write!(f, "the transaction id has a leading zero"),
+            IdError::TooLong(len) => write!(
+                f,
+                "the transaction id is {} bytes long, at most {} are allowed",
+                len,
+                TxID::MAX_LEN
+            ),
+            IdError::Invalid(c) => write!(f, "the transaction id contains {:?}", c),
+        }
+    }
 }
 
-type ClientID = u16;
-type TxID = u32;
+impl error::Error for IdError {}
 
 // This pattern below is using Rust's
 // type system as a state machine
@@ -234,7 +1440,7 @@ pub struct Transaction<T> {
     t: T,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone, Debug, Deserialize, Serialize)]
 pub struct TransactionData {
     // An enum is more memory efficient and easier to work with
     // compared to a String
@@ -243,12 +1449,58 @@ pub struct TransactionData {
     client: ClientID,
     tx: TxID,
     amount: Option<Decimal>,
+    // who ordered an administrative operation and why
+    // the columns can be left out of a feed without them
+    #[serde(default)]
+    operator: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
 }
 
 impl TransactionData {
+    /// Puts the data of an action together, as the feed or the log has it.
+    /// The operator and the reason only belong to administrative operations.
+    pub fn from_parts(
+        t_type: TransactionType,
+        client: ClientID,
+        tx: TxID,
+        amount: Option<Decimal>,
+        operator: Option<String>,
+        reason: Option<String>,
+    ) -> Self {
+        Self {
+            t_type,
+            client,
+            tx,
+            amount,
+            operator,
+            reason,
+        }
+    }
+
     pub fn tx_type(&self) -> TransactionType {
         self.t_type
     }
+
+    pub fn client(&self) -> ClientID {
+        self.client
+    }
+
+    pub fn tx(&self) -> &TxID {
+        &self.tx
+    }
+
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    pub fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
 }
 
 impl<T> Action<T> for Transaction<Deposit>
@@ -257,14 +1509,19 @@ where
 {
     fn apply(self, accts: &mut T) -> Result<(), ActionError> {
         let mut acc = accts.get_or_create(&self.t.client)?;
-        check_is_locked(&acc)?;
-        check_tx_exists(&self.t.tx, &acc)?;
+        check_accepts(&acc, TransactionType::Deposit)?;
+        check_tx_exists(&self.t.tx, accts)?;
 
         acc.available += self.t.amount;
         acc.total += self.t.amount;
-        acc.deposits.push(self);
 
-        accts.save_account(acc);
+        let event = self.data();
+        accts.commit(
+            Changeset::new()
+                .account(acc)
+                .record(Record::Deposit(self))
+                .log(event),
+        )?;
         Ok(())
     }
 }
@@ -277,6 +1534,103 @@ pub enum InnerError {
     InvalidType(TransactionType),
     MissingAmount,
     HasAmount,
+    // an administrative operation names no operator
+    MissingOperator,
+    // an administrative operation gives no reason
+    MissingReason,
+    // an administrative operation was not allowed in
+    Unauthorized(TransactionType),
+}
+
+impl InnerError {
+    // the name of the variant, stable enough to be used in reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InnerError::InvalidType(_) => "InvalidType",
+            InnerError::MissingAmount => "MissingAmount",
+            InnerError::HasAmount => "HasAmount",
+            InnerError::MissingOperator => "MissingOperator",
+            InnerError::MissingReason => "MissingReason",
+            InnerError::Unauthorized(_) => "Unauthorized",
+        }
+    }
+}
+
+impl fmt::Display for InnerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InnerError::InvalidType(t) => write!(f, "unexpected transaction type {:?}", t),
+            InnerError::MissingAmount => write!(f, "the amount is missing"),
+            InnerError::HasAmount => write!(f, "this transaction cannot have an amount"),
+            InnerError::MissingOperator => write!(f, "the operator is missing"),
+            InnerError::MissingReason => write!(f, "the reason is missing"),
+            InnerError::Unauthorized(t) => {
+                write!(
+                    f,
+                    "{:?} is an administrative operation, it is not allowed",
+                    t
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for InnerError {}
+
+/// Why [`Accounts::process`] did not apply a transaction.
+#[derive(Debug)]
+pub enum ProcessError {
+    // the data does not make a valid transaction
+    Validation(InnerError),
+    // the transaction could not be applied to the account
+    Apply(ActionError),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Validation(e) => e.fmt(f),
+            ProcessError::Apply(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ProcessError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ProcessError::Validation(e) => Some(e),
+            ProcessError::Apply(e) => Some(e),
+        }
+    }
+}
+
+/// Why [`Accounts::replay`] stopped.
+#[derive(Debug)]
+pub enum ReplayError {
+    // the log cannot be read
+    Read(ActionError),
+    // an event that was applied once is rejected now
+    Rejected { seq: u64, error: ProcessError },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Read(e) => write!(f, "cannot read the event log: {}", e),
+            ReplayError::Rejected { seq, error } => {
+                write!(f, "event {} is rejected: {}", seq, error)
+            }
+        }
+    }
+}
+
+impl error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReplayError::Read(e) => Some(e),
+            ReplayError::Rejected { error, .. } => Some(error),
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
@@ -286,11 +1640,6 @@ pub struct Deposit {
     amount: Decimal,
 }
 
-#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
-struct Disputed {
-    deposit: Transaction<Deposit>,
-}
-
 impl Transaction<Deposit> {
     pub fn new(t: TransactionData) -> Result<Self, InnerError> {
         if t.t_type != TransactionType::Deposit {
@@ -307,67 +1656,191 @@ impl Transaction<Deposit> {
         Ok(Self { t: deposit })
     }
 
-    fn dispute(self, d: Transaction<Dispute>) -> Result<Disputed, ActionError> {
-        if d.t.tx != self.t.tx {
-            return Err(ActionError::InvalidTxID);
+    /// Puts a stored deposit back together.
+    pub fn from_parts(client: ClientID, tx: TxID, amount: Decimal) -> Self {
+        Self {
+            t: Deposit { client, tx, amount },
         }
+    }
 
-        if d.t.client != self.t.client {
-            return Err(ActionError::InvalidClientID);
+    // the data the transaction was made from, for the event log
+    fn data(&self) -> TransactionData {
+        TransactionData {
+            t_type: TransactionType::Deposit,
+            client: self.t.client,
+            tx: self.t.tx.clone(),
+            amount: Some(self.t.amount),
+            operator: None,
+            reason: None,
         }
+    }
 
-        Ok(Disputed { deposit: self })
+    fn dispute(self, d: Transaction<Dispute>) -> Result<Disputed, ActionError> {
+        check_same_tx(&self.t.client, &self.t.tx, &d.t.client, &d.t.tx)?;
+        Ok(Disputed::Deposit(self))
     }
 }
 
+// A disputed transaction is waiting on an outcome.
+// It can either be resolved, which keeps the original transaction
+// or charged back, which reverses it
+//
+// The balances move differently depending on what is disputed.
+// For a deposit the amount is moved from available to held.
+// For a withdrawal the client claims the debit was unauthorized,
+// so the contested amount is provisionally credited as held funds
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum Disputed {
+    Deposit(Transaction<Deposit>),
+    Withdrawal(Transaction<Withdrawal>),
+}
+
 impl Disputed {
-    fn resolve(self, r: Resolve) -> Result<Resolved, ActionError> {
-        if r.tx != self.deposit.t.tx {
-            return Err(ActionError::InvalidTxID);
+    pub fn client(&self) -> ClientID {
+        match self {
+            Disputed::Deposit(d) => d.t.client,
+            Disputed::Withdrawal(w) => w.t.client,
+        }
+    }
+
+    pub fn tx(&self) -> &TxID {
+        match self {
+            Disputed::Deposit(d) => &d.t.tx,
+            Disputed::Withdrawal(w) => &w.t.tx,
         }
+    }
 
-        if r.client != self.deposit.t.client {
-            return Err(ActionError::InvalidClientID);
+    pub fn amount(&self) -> Decimal {
+        match self {
+            Disputed::Deposit(d) => d.t.amount,
+            Disputed::Withdrawal(w) => w.t.amount,
         }
+    }
 
+    fn resolve(self, r: Resolve) -> Result<Resolved, ActionError> {
+        check_same_tx(&self.client(), self.tx(), &r.client, &r.tx)?;
         Ok(Resolved { disputed: self })
     }
+
+    fn chargeback(self, c: Chargeback) -> Result<Chargedback, ActionError> {
+        check_same_tx(&self.client(), self.tx(), &c.client, &c.tx)?;
+        Ok(Chargedback { disputed: self })
+    }
+}
+
+fn check_same_tx(
+    client: &ClientID,
+    tx: &TxID,
+    other_client: &ClientID,
+    other_tx: &TxID,
+) -> Result<(), ActionError> {
+    if tx != other_tx {
+        return Err(ActionError::InvalidTxID);
+    }
+
+    if client != other_client {
+        return Err(ActionError::InvalidClientID);
+    }
+    Ok(())
 }
 
+// Both of these are final states
+// there is no transition out of them
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
-struct Resolved {
+pub struct Resolved {
     disputed: Disputed,
 }
 
 impl Resolved {
-    fn chargeback(self, r: Chargeback) -> Result<Chargedback, ActionError> {
-        if r.tx != self.disputed.deposit.t.tx {
-            return Err(ActionError::InvalidTxID);
-        }
+    pub fn new(disputed: Disputed) -> Self {
+        Self { disputed }
+    }
 
-        if r.client != self.disputed.deposit.t.client {
-            return Err(ActionError::InvalidClientID);
-        }
+    pub fn disputed(&self) -> &Disputed {
+        &self.disputed
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Chargedback {
+    disputed: Disputed,
+}
+
+impl Chargedback {
+    pub fn new(disputed: Disputed) -> Self {
+        Self { disputed }
+    }
 
-        Ok(Chargedback { resolved: self })
+    pub fn disputed(&self) -> &Disputed {
+        &self.disputed
     }
 }
 
-#[allow(unused)]
-struct Chargedback {
-    resolved: Resolved,
+/// A stored transaction in the state it currently is.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum Record {
+    Deposit(Transaction<Deposit>),
+    Withdrawal(Transaction<Withdrawal>),
+    Disputed(Disputed),
+    Resolved(Resolved),
+    Chargedback(Chargedback),
+    // an administrative operation, kept so its transaction id stays taken
+    Ordered(Ordered),
+}
+
+impl Record {
+    pub fn client(&self) -> ClientID {
+        match self {
+            Record::Deposit(d) => d.t.client,
+            Record::Withdrawal(w) => w.t.client,
+            Record::Disputed(d) => d.client(),
+            Record::Resolved(r) => r.disputed.client(),
+            Record::Chargedback(c) => c.disputed.client(),
+            Record::Ordered(o) => o.client,
+        }
+    }
+
+    pub fn tx(&self) -> &TxID {
+        match self {
+            Record::Deposit(d) => &d.t.tx,
+            Record::Withdrawal(w) => &w.t.tx,
+            Record::Disputed(d) => d.tx(),
+            Record::Resolved(r) => r.disputed.tx(),
+            Record::Chargedback(c) => c.disputed.tx(),
+            Record::Ordered(o) => &o.tx,
+        }
+    }
+
+    pub fn amount(&self) -> Decimal {
+        match self {
+            Record::Deposit(d) => d.t.amount,
+            Record::Withdrawal(w) => w.t.amount,
+            Record::Disputed(d) => d.amount(),
+            Record::Resolved(r) => r.disputed.amount(),
+            Record::Chargedback(c) => c.disputed.amount(),
+            Record::Ordered(o) => o.amount.unwrap_or_default(),
+        }
+    }
 }
 
 #[inline(always)]
-fn check_is_locked(acc: &Account) -> Result<(), ActionError> {
-    if acc.locked {
+// every state takes some of the transactions, see State::accepts
+fn check_accepts(acc: &Account, t: TransactionType) -> Result<(), ActionError> {
+    if acc.state().accepts(t) {
+        Ok(())
+    } else {
         Err(ActionError::AccountLocked)
+    }
+}
+
+fn check_in_debt(acc: &Account) -> Result<(), ActionError> {
+    if acc.in_debt() {
+        Err(ActionError::InDebt)
     } else {
         Ok(())
     }
 }
 
-#[allow(unused)]
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Withdrawal {
     client: ClientID,
@@ -390,6 +1863,29 @@ impl Transaction<Withdrawal> {
             },
         })
     }
+
+    /// Puts a stored withdrawal back together.
+    pub fn from_parts(client: ClientID, tx: TxID, amount: Decimal) -> Self {
+        Self {
+            t: Withdrawal { client, tx, amount },
+        }
+    }
+
+    fn data(&self) -> TransactionData {
+        TransactionData {
+            t_type: TransactionType::Withdrawal,
+            client: self.t.client,
+            tx: self.t.tx.clone(),
+            amount: Some(self.t.amount),
+            operator: None,
+            reason: None,
+        }
+    }
+
+    fn dispute(self, d: Transaction<Dispute>) -> Result<Disputed, ActionError> {
+        check_same_tx(&self.t.client, &self.t.tx, &d.t.client, &d.t.tx)?;
+        Ok(Disputed::Withdrawal(self))
+    }
 }
 
 #[inline(always)]
@@ -401,38 +1897,54 @@ fn check_sufficient_funds(amount: &Decimal, acc: &Account) -> Result<(), ActionE
     }
 }
 
-fn check_tx_exists(tx: &TxID, acc: &Account) -> Result<(), ActionError> {
-    let is_deposit = acc.deposits.iter().find(|&a| a.t.tx == *tx).is_some();
-    let is_withdrawal = acc.withdrawals.iter().find(|&a| a.t.tx == *tx).is_some();
-
-    if is_deposit || is_withdrawal {
+// transaction ids are unique across all clients
+// and they stay taken, whatever state the transaction is in
+// even once it is archived
+fn check_tx_exists<T: Container>(tx: &TxID, accts: &T) -> Result<(), ActionError> {
+    if accts.transaction(tx)?.is_some() || accts.archived(tx)?.is_some() {
         return Err(ActionError::InvalidTxID);
     }
     Ok(())
 }
 
+// disputes, resolves and chargebacks can only name
+// a transaction of the same client
+// an archived one is settled, it cannot be found here
+fn find_tx<T: Container>(tx: &TxID, client: &ClientID, accts: &T) -> Result<Record, ActionError> {
+    match accts.transaction(tx)? {
+        None => Err(ActionError::InvalidTxID),
+        Some(r) if r.client() != *client => Err(ActionError::ClientMismatch),
+        Some(r) => Ok(r),
+    }
+}
+
 impl<T> Action<T> for Transaction<Withdrawal>
 where
     T: Container,
 {
     fn apply(self, accts: &mut T) -> Result<(), ActionError> {
         let mut acc = accts.get_account(&self.t.client)?;
-        check_is_locked(&acc)?;
-        check_tx_exists(&self.t.tx, &acc)?;
+        check_accepts(&acc, TransactionType::Withdrawal)?;
+        check_in_debt(&acc)?;
+        check_tx_exists(&self.t.tx, accts)?;
         check_sufficient_funds(&self.t.amount, &acc)?;
 
         acc.available = check_div_negative(&acc.available, &self.t.amount)?;
         acc.total = check_div_negative(&acc.total, &self.t.amount)?;
-        acc.withdrawals.push(self);
 
-        accts.save_account(acc);
+        let event = self.data();
+        accts.commit(
+            Changeset::new()
+                .account(acc)
+                .record(Record::Withdrawal(self))
+                .log(event),
+        )?;
 
         Ok(())
     }
 }
 
-//What can actually be disputed?
-// From the description, it looks like only a deposit can be
+// Both deposits and withdrawals can be disputed
 pub struct Dispute {
     client: ClientID,
     tx: TxID,
@@ -455,6 +1967,17 @@ impl Transaction<Dispute> {
             },
         })
     }
+
+    fn data(&self) -> TransactionData {
+        TransactionData {
+            t_type: TransactionType::Dispute,
+            client: self.t.client,
+            tx: self.t.tx.clone(),
+            amount: None,
+            operator: None,
+            reason: None,
+        }
+    }
 }
 
 impl<T> Action<T> for Transaction<Dispute>
@@ -462,24 +1985,38 @@ where
     T: Container,
 {
     fn apply(self, accts: &mut T) -> Result<(), ActionError> {
-        let mut acc = accts.get_account(&self.t.client)?;
-        check_is_locked(&acc)?;
-        let pos = acc
-            .deposits
-            .iter()
-            .position(|e| e.t.tx == self.t.tx)
-            .ok_or(ActionError::InvalidTxID)?;
-
-        let tx = acc.deposits.remove(pos);
+        self.apply_with(accts, NegativeBalance::default())
+    }
 
-        let amount = tx.t.amount;
-        let disputed = tx.dispute(self)?;
+    fn apply_with(self, accts: &mut T, negative: NegativeBalance) -> Result<(), ActionError> {
+        let event = self.data();
+        let mut acc = accts.get_account(&self.t.client)?;
+        check_accepts(&acc, TransactionType::Dispute)?;
+        let disputed = match find_tx(&self.t.tx, &self.t.client, accts)? {
+            Record::Deposit(d) => d.dispute(self)?,
+            Record::Withdrawal(w) => w.dispute(self)?,
+            // already disputed or settled, or an administrative operation
+            _ => return Err(ActionError::InvalidTxID),
+        };
 
-        acc.disputes.push(disputed);
-        acc.available = check_div_negative(&acc.available, &amount)?;
-        acc.held += amount;
+        let amount = disputed.amount();
+        match disputed {
+            Disputed::Deposit(_) => {
+                acc.available = debit(&acc.available, &amount, negative)?;
+                acc.held += amount;
+            }
+            Disputed::Withdrawal(_) => {
+                acc.held += amount;
+                acc.total += amount;
+            }
+        }
 
-        accts.save_account(acc);
+        accts.commit(
+            Changeset::new()
+                .account(acc)
+                .record(Record::Disputed(disputed))
+                .log(event),
+        )?;
 
         Ok(())
     }
@@ -507,6 +2044,17 @@ impl Transaction<Resolve> {
             },
         })
     }
+
+    fn data(&self) -> TransactionData {
+        TransactionData {
+            t_type: TransactionType::Resolve,
+            client: self.t.client,
+            tx: self.t.tx.clone(),
+            amount: None,
+            operator: None,
+            reason: None,
+        }
+    }
 }
 
 impl<T> Action<T> for Transaction<Resolve>
@@ -514,31 +2062,40 @@ where
     T: Container,
 {
     fn apply(self, accts: &mut T) -> Result<(), ActionError> {
+        let event = self.data();
         let mut acc = accts.get_account(&self.t.client)?;
-        check_is_locked(&acc)?;
-        let pos = acc
-            .disputes
-            .iter()
-            .position(|e| e.deposit.t.tx == self.t.tx)
-            .ok_or(ActionError::InvalidTxID)?;
+        check_accepts(&acc, TransactionType::Resolve)?;
+        let tx = match find_tx(&self.t.tx, &self.t.client, accts)? {
+            Record::Disputed(d) => d,
+            _ => return Err(ActionError::InvalidTxID),
+        };
 
-        let tx = acc.disputes.remove(pos);
-        let amount = tx.deposit.t.amount;
+        let amount = tx.amount();
         let resolved = tx.resolve(self.t)?;
 
-        acc.resolves.push(resolved);
         acc.held = check_div_negative(&acc.held, &amount)?;
-        acc.available += amount;
+        match resolved.disputed {
+            // the deposit stands, release the hold
+            Disputed::Deposit(_) => acc.available += amount,
+            // the withdrawal stands, drop the provisional credit
+            Disputed::Withdrawal(_) => {
+                acc.total = check_div_negative(&acc.total, &amount)?;
+            }
+        }
 
-        accts.save_account(acc);
+        accts.commit(
+            Changeset::new()
+                .account(acc)
+                .record(Record::Resolved(resolved))
+                .log(event),
+        )?;
 
         Ok(())
     }
 }
 
-// the resolve already decreases the held amount
-// but the description of chargeback says the held funds
-// decrease too
+// A chargeback can only happen on a disputed transaction
+// it reverses the transaction and locks the account
 pub struct Chargeback {
     client: ClientID,
     tx: TxID,
@@ -561,6 +2118,17 @@ impl Transaction<Chargeback> {
             },
         })
     }
+
+    fn data(&self) -> TransactionData {
+        TransactionData {
+            t_type: TransactionType::Chargeback,
+            client: self.t.client,
+            tx: self.t.tx.clone(),
+            amount: None,
+            operator: None,
+            reason: None,
+        }
+    }
 }
 
 fn check_div_negative(a: &Decimal, b: &Decimal) -> Result<Decimal, ActionError> {
@@ -572,118 +2140,362 @@ fn check_div_negative(a: &Decimal, b: &Decimal) -> Result<Decimal, ActionError>
     Ok(c)
 }
 
-impl<T> Action<T> for Transaction<Chargeback>
+// takes back funds the client may have spent already
+fn debit(a: &Decimal, b: &Decimal, negative: NegativeBalance) -> Result<Decimal, ActionError> {
+    match negative {
+        NegativeBalance::Refuse => check_div_negative(a, b),
+        NegativeBalance::Allow => Ok(a - b),
+    }
+}
+
+impl<T> Action<T> for Transaction<Chargeback>
 where
     T: Container,
 {
     fn apply(self, accts: &mut T) -> Result<(), ActionError> {
+        self.apply_with(accts, NegativeBalance::default())
+    }
+
+    fn apply_with(self, accts: &mut T, negative: NegativeBalance) -> Result<(), ActionError> {
+        let event = self.data();
         let mut acc = accts.get_account(&self.t.client)?;
-        check_is_locked(&acc)?;
+        check_accepts(&acc, TransactionType::Chargeback)?;
+        let tx = match find_tx(&self.t.tx, &self.t.client, accts)? {
+            Record::Disputed(d) => d,
+            _ => return Err(ActionError::InvalidTxID),
+        };
 
-        let pos = acc
-            .resolves
-            .iter()
-            .position(|e| e.disputed.deposit.t.tx == self.t.tx)
-            .ok_or(ActionError::InvalidTxID)?;
+        let amount = tx.amount();
+        let chargedback = tx.chargeback(self.t)?;
 
-        let tx = acc.resolves.remove(pos);
-        let amount = tx.disputed.deposit.t.amount;
-        // this is the final state so we don't need to store anything
-        // at least for this task
-        let _ = tx.chargeback(self.t)?;
-        acc.available = check_div_negative(&acc.available, &amount)?;
-        acc.total = check_div_negative(&acc.total, &amount)?;
-        acc.locked = true;
+        acc.held = check_div_negative(&acc.held, &amount)?;
+        match chargedback.disputed {
+            // the deposit is reversed, the client took money
+            // that was never theirs, the first such chargeback
+            // is what froze the account
+            Disputed::Deposit(_) => {
+                acc.total = debit(&acc.total, &amount, negative)?;
+                if acc.state() != State::Frozen {
+                    acc.status = Status::Frozen(Cause::new("chargeback", Some(event.tx.clone())));
+                }
+            }
+            // the withdrawal is reversed, the client gets the funds back
+            // and did nothing wrong, the account stays as it is
+            Disputed::Withdrawal(_) => acc.available += amount,
+        }
 
-        accts.save_account(acc);
+        accts.commit(
+            Changeset::new()
+                .account(acc)
+                .record(Record::Chargedback(chargedback))
+                .log(event),
+        )?;
 
         Ok(())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use rust_decimal::prelude::FromPrimitive;
-    use std::collections::HashMap;
+// What every administrative operation carries
+// the operator and the reason are logged with it
+pub struct Order {
+    client: ClientID,
+    tx: TxID,
+    // only a close has one, the payout
+    amount: Option<Decimal>,
+    operator: String,
+    reason: String,
+}
+
+impl Order {
+    fn new(t: TransactionData, t_type: TransactionType) -> Result<Self, InnerError> {
+        if t.t_type != t_type {
+            return Err(InnerError::InvalidType(t.t_type));
+        }
+
+        if t.amount.is_some() && t_type != TransactionType::Close {
+            return Err(InnerError::HasAmount);
+        }
 
-    #[derive(Default)]
-    struct MockContainer {
-        data: HashMap<ClientID, Account>,
+        let operator = t
+            .operator
+            .filter(|o| !o.is_empty())
+            .ok_or(InnerError::MissingOperator)?;
+        let reason = t
+            .reason
+            .filter(|r| !r.is_empty())
+            .ok_or(InnerError::MissingReason)?;
+
+        Ok(Self {
+            client: t.client,
+            tx: t.tx,
+            amount: t.amount,
+            operator,
+            reason,
+        })
     }
 
-    impl Container for MockContainer {
-        fn get_or_create(&self, id: &ClientID) -> Result<Account, ActionError> {
-            match self.data.get(id) {
-                Some(s) => Ok(s.clone()),
-                None => Ok(Account::new(*id)),
-            }
+    fn data(&self, t_type: TransactionType) -> TransactionData {
+        TransactionData {
+            t_type,
+            client: self.client,
+            tx: self.tx.clone(),
+            amount: self.amount,
+            operator: Some(self.operator.clone()),
+            reason: Some(self.reason.clone()),
+        }
+    }
+
+    fn cause(&self) -> Cause {
+        Cause::new(self.reason.clone(), Some(self.tx.clone()))
+    }
+
+    fn record(&self, t_type: TransactionType) -> Record {
+        Record::Ordered(Ordered {
+            t_type,
+            client: self.client,
+            tx: self.tx.clone(),
+            amount: self.amount,
+        })
+    }
+
+    // moves the account to the status the operation puts it in
+    fn apply<T: Container>(
+        mut self,
+        t_type: TransactionType,
+        accts: &mut T,
+        status: impl FnOnce(&mut Account, Cause) -> Result<Status, ActionError>,
+    ) -> Result<(), ActionError> {
+        let mut acc = accts.get_account(&self.client)?;
+        check_accepts(&acc, t_type)?;
+        check_tx_exists(&self.tx, accts)?;
+        let total = acc.total;
+        acc.status = status(&mut acc, self.cause())?;
+
+        // a close pays out, what it paid is kept with it
+        if t_type == TransactionType::Close {
+            self.amount = Some(total - acc.total);
         }
 
-        fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
-            self.data
-                .get(id)
-                .ok_or(ActionError::InvalidClientID)
-                .map(|a| a.clone())
+        accts.commit(
+            Changeset::new()
+                .account(acc)
+                .record(self.record(t_type))
+                .log(self.data(t_type)),
+        )?;
+        Ok(())
+    }
+}
+
+/// A stored administrative operation.
+/// It cannot be disputed, it only takes up its transaction id.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Ordered {
+    t_type: TransactionType,
+    client: ClientID,
+    tx: TxID,
+    amount: Option<Decimal>,
+}
+
+impl Ordered {
+    /// Puts a stored administrative operation back together,
+    /// `amount` is the payout of a close.
+    pub fn from_parts(
+        t_type: TransactionType,
+        client: ClientID,
+        tx: TxID,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Self {
+            t_type,
+            client,
+            tx,
+            amount,
+        }
+    }
+
+    pub fn t_type(&self) -> TransactionType {
+        self.t_type
+    }
+
+    /// What a close paid out, the other operations move no money.
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+}
+
+macro_rules! admin {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        pub struct $name {
+            order: Order,
         }
 
-        fn save_account(&mut self, acc: Account) {
-            self.data.insert(acc.client, acc);
+        impl Transaction<$name> {
+            pub fn new(t: TransactionData) -> Result<Self, InnerError> {
+                let order = Order::new(t, TransactionType::$name)?;
+                Ok(Self { t: $name { order } })
+            }
+
+            pub fn operator(&self) -> &str {
+                &self.t.order.operator
+            }
+
+            pub fn reason(&self) -> &str {
+                &self.t.order.reason
+            }
         }
+    };
+}
+
+admin!(
+    /// Makes a frozen or suspended account active again.
+    Unlock
+);
+admin!(
+    /// Freezes the account, see [`State::Frozen`].
+    Freeze
+);
+admin!(
+    /// Suspends the account, see [`State::Suspended`].
+    Suspend
+);
+admin!(
+    /// Pays out what is available and closes the account for good.
+    /// An account with open disputes or in debt cannot be closed.
+    /// The payout is logged as the amount, a close that names one
+    /// is refused unless it is what is available.
+    Close
+);
+
+impl<T> Action<T> for Transaction<Unlock>
+where
+    T: Container,
+{
+    fn apply(self, accts: &mut T) -> Result<(), ActionError> {
+        self.t
+            .order
+            .apply(TransactionType::Unlock, accts, |_, _| Ok(Status::Active))
+    }
+}
+
+impl<T> Action<T> for Transaction<Freeze>
+where
+    T: Container,
+{
+    fn apply(self, accts: &mut T) -> Result<(), ActionError> {
+        self.t
+            .order
+            .apply(TransactionType::Freeze, accts, |_, cause| {
+                Ok(Status::Frozen(cause))
+            })
     }
+}
+
+impl<T> Action<T> for Transaction<Suspend>
+where
+    T: Container,
+{
+    fn apply(self, accts: &mut T) -> Result<(), ActionError> {
+        self.t
+            .order
+            .apply(TransactionType::Suspend, accts, |_, cause| {
+                Ok(Status::Suspended(cause))
+            })
+    }
+}
+
+impl<T> Action<T> for Transaction<Close>
+where
+    T: Container,
+{
+    fn apply(self, accts: &mut T) -> Result<(), ActionError> {
+        let payout = self.t.order.amount;
+        self.t
+            .order
+            .apply(TransactionType::Close, accts, |acc, cause| {
+                if !acc.held.is_zero() {
+                    return Err(ActionError::OpenDisputes);
+                }
+                check_in_debt(acc)?;
+                // a payout on record, replayed or named by the operator,
+                // has to be what is there to pay out
+                if payout.is_some_and(|p| p != acc.available) {
+                    return Err(ActionError::PayoutMismatch);
+                }
+
+                // the payout is whatever is left
+                acc.total -= acc.available;
+                acc.available = Decimal::from(0);
+                Ok(Status::Closed(cause))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::conformance::data;
+    use super::*;
+    use rust_decimal::prelude::FromPrimitive;
 
     #[test]
     fn deposit() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
         actts.handle(tx.clone()).unwrap();
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
 
         let mut tx2 = tx.clone();
-        tx2.t.tx += 1;
+        tx2.t.tx = TxID::numeric(2);
 
         let mut expect = Account {
-            client: 1,
+            client: ClientID(1),
             available: Decimal::from(1),
             held: Decimal::from(0),
             total: Decimal::from(1),
-            locked: false,
-            deposits: vec![tx.clone()],
-            withdrawals: vec![],
-            disputes: vec![],
-            resolves: vec![],
+            status: Status::Active,
         };
 
         assert_eq!(acc, expect);
+        assert_eq!(
+            actts.db.transaction(&TxID::numeric(1)),
+            Ok(Some(Record::Deposit(tx)))
+        );
 
-        expect.deposits.push(tx2.clone());
         actts.handle(tx2.clone()).unwrap();
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
         expect.available += Decimal::from(1);
         expect.total += Decimal::from(1);
 
         assert_eq!(acc, expect);
+        assert_eq!(
+            actts.db.transaction(&TxID::numeric(2)),
+            Ok(Some(Record::Deposit(tx2)))
+        );
     }
 
     #[test]
     fn duplicate_deposit() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
         actts.handle(tx.clone()).unwrap();
         let err = actts.handle(tx.clone()).expect_err("duplicate deposit");
@@ -694,61 +2506,69 @@ mod test {
     fn withdrawal() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
         actts.handle(tx.clone()).unwrap();
 
         let withdrawal = Transaction::<Withdrawal>::new(TransactionData {
             t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
             amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
         actts.handle(withdrawal.clone()).unwrap();
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
 
         let expect = Account {
-            client: 1,
+            client: ClientID(1),
             available: Default::default(),
             held: Default::default(),
             total: Default::default(),
-            locked: false,
-            deposits: vec![tx.clone()],
-            withdrawals: vec![withdrawal],
-            disputes: vec![],
-            resolves: vec![],
+            status: Status::Active,
         };
 
         assert_eq!(acc, expect);
+        assert_eq!(
+            actts.db.transaction(&TxID::numeric(2)),
+            Ok(Some(Record::Withdrawal(withdrawal)))
+        );
     }
 
     #[test]
     fn withdrawal_negative() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
         actts.handle(tx.clone()).unwrap();
 
         let withdrawal = Transaction::<Withdrawal>::new(TransactionData {
             t_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
             amount: Some(Decimal::from(2)),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
@@ -764,75 +2584,157 @@ mod test {
     fn decimal_format() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from_f64(1.11111).unwrap()),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
         actts.handle(tx.clone()).unwrap();
 
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
         let acc_data: AccountData = acc.into();
 
         assert_eq!(
             acc_data,
             AccountData {
-                client: 1,
+                client: ClientID(1),
                 available: Decimal::from_f64(1.1111).unwrap(),
                 held: Default::default(),
                 total: Decimal::from_f64(1.1111).unwrap(),
-                locked: false
+                locked: false,
+                state: State::Active,
+                in_debt: false,
             }
         );
     }
 
+    #[test]
+    fn ids_are_parsed_without_truncation() {
+        assert_eq!("4294967296".parse(), Ok(TxID::numeric(1 << 32)));
+        assert_eq!("18446744073709551615".parse(), Ok(TxID::numeric(u64::MAX)));
+        assert_eq!(
+            "18446744073709551616".parse::<TxID>(),
+            Err(IdError::Overflow)
+        );
+        assert_eq!("0".parse(), Ok(TxID::numeric(0)));
+        // neither taken for 123 nor kept apart from it
+        assert_eq!("0123".parse::<TxID>(), Err(IdError::LeadingZero));
+        assert_eq!("00".parse::<TxID>(), Err(IdError::LeadingZero));
+        assert_eq!("".parse::<TxID>(), Err(IdError::Empty));
+        assert_eq!("-1".parse::<TxID>().unwrap().as_opaque(), Some("-1"));
+        assert_eq!(
+            "x".repeat(TxID::MAX_LEN + 1).parse::<TxID>(),
+            Err(IdError::TooLong(TxID::MAX_LEN + 1))
+        );
+        assert_eq!("ref 1".parse::<TxID>(), Err(IdError::Invalid(' ')));
+
+        let csv = "type,client,tx,amount
+            deposit,18446744073709551615,9b2f6c1e-3d4a-4e8b-a1c2-7f0e5d6b8a90,1
+            deposit,65536,4294967296,2
+            deposit,18446744073709551616,1,1
+            deposit,1,18446744073709551616,1
+            deposit,1,0123,1
+            deposit,1,18446744073709551615,1
+";
+        let mut r = csv::ReaderBuilder::default()
+            .trim(csv::Trim::All)
+            .from_reader(csv.as_bytes());
+        let rows = r
+            .deserialize::<TransactionData>()
+            .map(|res| res.map(|td| (td.client, td.tx)).map_err(|_| ()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                Ok((
+                    ClientID(u64::MAX),
+                    "9b2f6c1e-3d4a-4e8b-a1c2-7f0e5d6b8a90".parse().unwrap()
+                )),
+                Ok((ClientID(65536), TxID::numeric(1 << 32))),
+                Err(()),
+                Err(()),
+                Err(()),
+                Ok((ClientID(1), TxID::numeric(u64::MAX))),
+            ]
+        );
+
+        // the full range makes it to the keys of the store
+        let mut actts = Accounts::new(
+            DB::ephemeral(sled::Config::new().temporary(true).open().unwrap()).unwrap(),
+        );
+        for (client, tx) in rows.into_iter().flatten() {
+            actts
+                .process(TransactionData {
+                    t_type: TransactionType::Deposit,
+                    client,
+                    tx,
+                    amount: Some(Decimal::from(1)),
+                    operator: None,
+                    reason: None,
+                })
+                .unwrap();
+        }
+        let clients = actts
+            .iter()
+            .map(|acc| acc.unwrap().client)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            clients,
+            vec![ClientID(1), ClientID(65536), ClientID(u64::MAX)]
+        );
+        assert!(actts
+            .db
+            .transaction(&TxID::numeric(1 << 32))
+            .unwrap()
+            .is_some());
+    }
+
     #[test]
     fn cannot_use_frozen_account() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from_f64(1.0).unwrap()),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
         let dispute = Transaction::<Dispute>::new(TransactionData {
             t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
-            amount: None,
-        })
-        .unwrap();
-
-        let resolve = Transaction::<Resolve>::new(TransactionData {
-            t_type: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: None,
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
         let chargeback = Transaction::<Chargeback>::new(TransactionData {
             t_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: None,
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
 
         actts.handle(tx.clone()).unwrap();
         actts.handle(dispute).unwrap();
-        actts.handle(resolve).unwrap();
         actts.handle(chargeback).unwrap();
 
         let mut tx2 = tx.clone();
-        tx2.t.tx += 1;
+        tx2.t.tx = TxID::numeric(2);
 
         let err = actts
             .handle(tx2)
@@ -844,101 +2746,1081 @@ mod test {
     fn dispute_process() {
         let tx = Transaction::<Deposit>::new(TransactionData {
             t_type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: Some(Decimal::from_f64(1.0).unwrap()),
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
         let dispute = Transaction::<Dispute>::new(TransactionData {
             t_type: TransactionType::Dispute,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: None,
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
         let resolve = Transaction::<Resolve>::new(TransactionData {
             t_type: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
-            amount: None,
-        })
-        .unwrap();
-
-        let chargeback = Transaction::<Chargeback>::new(TransactionData {
-            t_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
             amount: None,
+            operator: None,
+            reason: None,
         })
         .unwrap();
 
-        let c = MockContainer::default();
+        let c = MemoryContainer::new();
         let mut actts = Accounts::new(c);
 
         actts.handle(tx.clone()).unwrap();
 
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
         let acc_data: AccountData = acc.into();
 
         assert_eq!(
             acc_data,
             AccountData {
-                client: 1,
+                client: ClientID(1),
                 available: Decimal::from_f64(1.0).unwrap(),
                 held: Default::default(),
                 total: Decimal::from_f64(1.0).unwrap(),
-                locked: false
+                locked: false,
+                state: State::Active,
+                in_debt: false,
             }
         );
 
         actts.handle(dispute).unwrap();
 
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
         let acc_data: AccountData = acc.into();
 
         assert_eq!(
             acc_data,
             AccountData {
-                client: 1,
+                client: ClientID(1),
                 available: Decimal::from(0),
                 held: Decimal::from(1),
                 total: Decimal::from(1),
-                locked: false
+                locked: false,
+                state: State::Active,
+                in_debt: false,
             }
         );
 
         actts.handle(resolve).unwrap();
 
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
         let acc_data: AccountData = acc.into();
 
         assert_eq!(
             acc_data,
             AccountData {
-                client: 1,
+                client: ClientID(1),
                 available: Decimal::from(1),
                 held: Decimal::from(0),
                 total: Decimal::from(1),
-                locked: false
+                locked: false,
+                state: State::Active,
+                in_debt: false,
             }
         );
+    }
 
+    #[test]
+    fn dispute_chargeback() {
+        let tx = Transaction::<Deposit>::new(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(3)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let dispute = Transaction::<Dispute>::new(TransactionData {
+            t_type: TransactionType::Dispute,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let chargeback = Transaction::<Chargeback>::new(TransactionData {
+            t_type: TransactionType::Chargeback,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let resolve = Transaction::<Resolve>::new(TransactionData {
+            t_type: TransactionType::Resolve,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let c = MemoryContainer::new();
+        let mut actts = Accounts::new(c);
+
+        actts.handle(tx).unwrap();
+        actts.handle(dispute).unwrap();
         actts.handle(chargeback).unwrap();
 
-        let acc = actts.db.get_account(&1).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
         let acc_data: AccountData = acc.into();
 
         assert_eq!(
             acc_data,
             AccountData {
-                client: 1,
+                client: ClientID(1),
                 available: Decimal::from(0),
                 held: Decimal::from(0),
                 total: Decimal::from(0),
-                locked: true
+                locked: true,
+                state: State::Frozen,
+                in_debt: false,
             }
         );
+
+        // the frozen account still takes resolves
+        // but a charged back deposit is in a final state
+        let err = actts
+            .handle(resolve)
+            .expect_err("cannot resolve a chargeback");
+        assert_eq!(err, ActionError::InvalidTxID);
+    }
+
+    #[test]
+    fn frozen_account_settles_open_disputes() {
+        let mut actts = Accounts::new(MemoryContainer::new());
+        for td in [
+            data(TransactionType::Deposit, 1, 1, Some(10)),
+            data(TransactionType::Deposit, 1, 2, Some(5)),
+            data(TransactionType::Deposit, 1, 3, Some(1)),
+            data(TransactionType::Dispute, 1, 1, None),
+            data(TransactionType::Dispute, 1, 2, None),
+            data(TransactionType::Dispute, 1, 3, None),
+            data(TransactionType::Chargeback, 1, 1, None),
+        ] {
+            actts.process(td).unwrap();
+        }
+
+        let frozen = Status::Frozen(Cause::new("chargeback", Some(TxID::numeric(1))));
+        assert_eq!(
+            actts.db.get_account(&ClientID(1)).unwrap().status(),
+            &frozen
+        );
+
+        // nothing moves
+        for td in [
+            data(TransactionType::Deposit, 1, 4, Some(1)),
+            data(TransactionType::Withdrawal, 1, 5, Some(1)),
+        ] {
+            assert!(matches!(
+                actts.process(td),
+                Err(ProcessError::Apply(ActionError::AccountLocked))
+            ));
+        }
+
+        // but the open disputes are still settled
+        actts
+            .process(data(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+        actts
+            .process(data(TransactionType::Chargeback, 1, 3, None))
+            .unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        assert_eq!(acc.available(), Decimal::from(5));
+        assert!(acc.held().is_zero());
+        assert_eq!(acc.status(), &frozen, "the first chargeback froze it");
+
+        assert!(State::Suspended.accepts(TransactionType::Deposit));
+        assert!(!State::Suspended.accepts(TransactionType::Withdrawal));
+        assert!(!State::Closed.accepts(TransactionType::Resolve));
+    }
+
+    #[test]
+    fn admin_operations() {
+        let order = |t_type, tx| TransactionData {
+            operator: Some("alice".to_string()),
+            reason: Some("support ticket".to_string()),
+            ..data(t_type, 1, tx, None)
+        };
+
+        // the client feed cannot order them
+        let mut actts = Accounts::new(MemoryContainer::new());
+        assert!(matches!(
+            actts.process(order(TransactionType::Freeze, 100)),
+            Err(ProcessError::Validation(InnerError::Unauthorized(
+                TransactionType::Freeze
+            )))
+        ));
+
+        let mut actts = Accounts::new(MemoryContainer::new()).with_admin(true);
+        for td in [
+            data(TransactionType::Deposit, 1, 1, Some(10)),
+            data(TransactionType::Deposit, 1, 2, Some(5)),
+            data(TransactionType::Dispute, 1, 2, None),
+            data(TransactionType::Chargeback, 1, 2, None),
+        ] {
+            actts.process(td).unwrap();
+        }
+        assert!(matches!(
+            actts.process(data(TransactionType::Unlock, 1, 100, None)),
+            Err(ProcessError::Validation(InnerError::MissingOperator))
+        ));
+        assert!(matches!(
+            actts.process(TransactionData {
+                reason: None,
+                ..order(TransactionType::Unlock, 100)
+            }),
+            Err(ProcessError::Validation(InnerError::MissingReason))
+        ));
+
+        // the chargeback froze it, an unlock lets it move again
+        actts.process(order(TransactionType::Unlock, 100)).unwrap();
+        actts
+            .process(data(TransactionType::Withdrawal, 1, 3, Some(2)))
+            .unwrap();
+
+        // the operations take their ids like any transaction
+        // and cannot be disputed
+        for td in [
+            order(TransactionType::Freeze, 1),
+            data(TransactionType::Deposit, 1, 100, Some(1)),
+            data(TransactionType::Dispute, 1, 100, None),
+        ] {
+            assert!(matches!(
+                actts.process(td),
+                Err(ProcessError::Apply(ActionError::InvalidTxID))
+            ));
+        }
+
+        actts.process(order(TransactionType::Freeze, 101)).unwrap();
+        let status = actts.db.get_account(&ClientID(1)).unwrap().status().clone();
+        assert_eq!(
+            status,
+            Status::Frozen(Cause::new("support ticket", Some(TxID::numeric(101))))
+        );
+        // the operator is found in the log by the transaction of the cause
+        let tx = status.cause().and_then(Cause::tx).unwrap();
+        let freeze = actts
+            .events()
+            .map(Result::unwrap)
+            .find(|e| e.data().tx() == tx)
+            .unwrap();
+        assert_eq!(freeze.data().operator(), Some("alice"));
+        assert!(matches!(
+            actts.process(data(TransactionType::Deposit, 1, 4, Some(1))),
+            Err(ProcessError::Apply(ActionError::AccountLocked))
+        ));
+
+        // a suspended account still takes deposits
+        // but it is not closed with a dispute open
+        actts.process(order(TransactionType::Suspend, 102)).unwrap();
+        for td in [
+            data(TransactionType::Deposit, 1, 6, Some(3)),
+            data(TransactionType::Dispute, 1, 6, None),
+        ] {
+            actts.process(td).unwrap();
+        }
+        assert!(matches!(
+            actts.process(order(TransactionType::Close, 103)),
+            Err(ProcessError::Apply(ActionError::OpenDisputes))
+        ));
+        actts
+            .process(data(TransactionType::Resolve, 1, 6, None))
+            .unwrap();
+
+        // what is available is paid out, a close naming
+        // another payout is refused
+        let available = actts.db.get_account(&ClientID(1)).unwrap().available();
+        assert!(matches!(
+            actts.process(TransactionData {
+                amount: Some(available + Decimal::from(1)),
+                ..order(TransactionType::Close, 104)
+            }),
+            Err(ProcessError::Apply(ActionError::PayoutMismatch))
+        ));
+        assert!(matches!(
+            actts.process(TransactionData {
+                amount: Some(Decimal::from(1)),
+                ..order(TransactionType::Freeze, 104)
+            }),
+            Err(ProcessError::Validation(InnerError::HasAmount))
+        ));
+        actts.process(order(TransactionType::Close, 104)).unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        assert_eq!(acc.state(), State::Closed);
+        assert!(acc.available().is_zero());
+        assert!(acc.total().is_zero());
+        match actts.db.transaction(&TxID::numeric(104)) {
+            Ok(Some(Record::Ordered(o))) => assert_eq!(o.amount(), Some(available)),
+            other => panic!("the close is not stored: {:?}", other),
+        }
+
+        for td in [
+            data(TransactionType::Deposit, 1, 5, Some(1)),
+            order(TransactionType::Unlock, 105),
+        ] {
+            assert!(matches!(
+                actts.process(td),
+                Err(ProcessError::Apply(ActionError::AccountLocked))
+            ));
+        }
+
+        // the log keeps who ordered what and the payout
+        let close = actts.events().last().unwrap().unwrap();
+        assert_eq!(close.data().amount(), Some(available));
+        assert!(!available.is_zero());
+        assert_eq!(close.data().operator(), Some("alice"));
+        assert_eq!(close.data().reason(), Some("support ticket"));
+
+        // and replays without the permission
+        let mut rebuilt = Accounts::new(MemoryContainer::new());
+        assert_eq!(rebuilt.replay(&actts.db).unwrap(), 12);
+        assert_eq!(
+            rebuilt.db.get_account(&ClientID(1)),
+            actts.db.get_account(&ClientID(1))
+        );
+    }
+
+    #[test]
+    fn chargeback_requires_dispute() {
+        let tx = Transaction::<Deposit>::new(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let chargeback = Transaction::<Chargeback>::new(TransactionData {
+            t_type: TransactionType::Chargeback,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let c = MemoryContainer::new();
+        let mut actts = Accounts::new(c);
+
+        actts.handle(tx).unwrap();
+        let err = actts
+            .handle(chargeback)
+            .expect_err("chargeback on an undisputed deposit");
+        assert_eq!(err, ActionError::InvalidTxID);
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolve() {
+        let deposit = Transaction::<Deposit>::new(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(5)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let withdrawal = Transaction::<Withdrawal>::new(TransactionData {
+            t_type: TransactionType::Withdrawal,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
+            amount: Some(Decimal::from(2)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let dispute = Transaction::<Dispute>::new(TransactionData {
+            t_type: TransactionType::Dispute,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let resolve = Transaction::<Resolve>::new(TransactionData {
+            t_type: TransactionType::Resolve,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let c = MemoryContainer::new();
+        let mut actts = Accounts::new(c);
+
+        actts.handle(deposit).unwrap();
+        actts.handle(withdrawal).unwrap();
+        actts.handle(dispute).unwrap();
+
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        let acc_data: AccountData = acc.into();
+
+        assert_eq!(
+            acc_data,
+            AccountData {
+                client: ClientID(1),
+                available: Decimal::from(3),
+                held: Decimal::from(2),
+                total: Decimal::from(5),
+                locked: false,
+                state: State::Active,
+                in_debt: false,
+            }
+        );
+
+        actts.handle(resolve).unwrap();
+
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        let acc_data: AccountData = acc.into();
+
+        assert_eq!(
+            acc_data,
+            AccountData {
+                client: ClientID(1),
+                available: Decimal::from(3),
+                held: Decimal::from(0),
+                total: Decimal::from(3),
+                locked: false,
+                state: State::Active,
+                in_debt: false,
+            }
+        );
+    }
+
+    #[test]
+    fn withdrawal_dispute_chargeback() {
+        let deposit = Transaction::<Deposit>::new(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(5)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let withdrawal = Transaction::<Withdrawal>::new(TransactionData {
+            t_type: TransactionType::Withdrawal,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
+            amount: Some(Decimal::from(2)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let dispute = Transaction::<Dispute>::new(TransactionData {
+            t_type: TransactionType::Dispute,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let chargeback = Transaction::<Chargeback>::new(TransactionData {
+            t_type: TransactionType::Chargeback,
+            client: ClientID(1),
+            tx: TxID::numeric(2),
+            amount: None,
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+
+        let c = MemoryContainer::new();
+        let mut actts = Accounts::new(c);
+
+        actts.handle(deposit).unwrap();
+        actts.handle(withdrawal).unwrap();
+        actts.handle(dispute).unwrap();
+        actts.handle(chargeback).unwrap();
+
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        let acc_data: AccountData = acc.into();
+
+        assert_eq!(
+            acc_data,
+            AccountData {
+                client: ClientID(1),
+                available: Decimal::from(5),
+                held: Decimal::from(0),
+                total: Decimal::from(5),
+                locked: false,
+                state: State::Active,
+                in_debt: false,
+            }
+        );
+    }
+
+    // sled lets go of its file lock from a background thread
+    // so opening the same path right after a drop can briefly fail
+    fn reopen(path: &std::path::Path) -> sled::Db {
+        for _ in 0..100 {
+            match sled::open(path) {
+                Ok(db) => return db,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        panic!("cannot open {:?}", path)
+    }
+
+    #[test]
+    fn chargeback_after_withdrawal_leaves_a_debt() {
+        // the deposit is withdrawn before it is disputed
+        let mut refused = Accounts::new(MemoryContainer::new());
+        refused
+            .process(data(TransactionType::Deposit, 1, 1, Some(10)))
+            .unwrap();
+        refused
+            .process(data(TransactionType::Withdrawal, 1, 2, Some(8)))
+            .unwrap();
+        assert!(matches!(
+            refused.process(data(TransactionType::Dispute, 1, 1, None)),
+            Err(ProcessError::Apply(ActionError::InsufficientFunds))
+        ));
+
+        let mut actts =
+            Accounts::new(MemoryContainer::new()).with_negative_balance(NegativeBalance::Allow);
+        actts
+            .process(data(TransactionType::Deposit, 1, 1, Some(10)))
+            .unwrap();
+        actts
+            .process(data(TransactionType::Withdrawal, 1, 2, Some(8)))
+            .unwrap();
+        actts
+            .process(data(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        assert_eq!(acc.available(), Decimal::from(-8));
+        assert_eq!(acc.held(), Decimal::from(10));
+        assert!(acc.in_debt());
+
+        // nothing goes out until the debt is paid
+        actts
+            .process(data(TransactionType::Deposit, 1, 3, Some(5)))
+            .unwrap();
+        assert!(matches!(
+            actts.process(data(TransactionType::Withdrawal, 1, 4, Some(1))),
+            Err(ProcessError::Apply(ActionError::InDebt))
+        ));
+
+        actts
+            .process(data(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+        let acc_data: AccountData = actts.db.get_account(&ClientID(1)).unwrap().into();
+        assert_eq!(
+            acc_data,
+            AccountData {
+                client: ClientID(1),
+                available: Decimal::from(-3),
+                held: Decimal::from(0),
+                total: Decimal::from(-3),
+                locked: true,
+                state: State::Frozen,
+                in_debt: true,
+            }
+        );
+
+        // the history only adds up when debts are allowed
+        let mut rebuilt =
+            Accounts::new(MemoryContainer::new()).with_negative_balance(NegativeBalance::Allow);
+        assert_eq!(rebuilt.replay(&actts.db).unwrap(), 5);
+        assert!(Accounts::new(MemoryContainer::new())
+            .replay(&actts.db)
+            .is_err());
+    }
+
+    #[test]
+    fn persistent_db_survives_drop() {
+        let path = std::env::temp_dir().join(format!("payments-persist-{}", std::process::id()));
+        let tx = Transaction::<Deposit>::new(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+        let open = || reopen(&path);
+
+        {
+            let mut actts = Accounts::new(DB::new(open()).unwrap());
+            actts.handle(tx).unwrap();
+        }
+
+        {
+            let actts = Accounts::new(DB::ephemeral(open()).unwrap());
+            assert_eq!(actts.iter().count(), 1);
+        }
+
+        let actts = Accounts::new(DB::new(open()).unwrap());
+        assert_eq!(actts.iter().count(), 0);
+        drop(actts);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn memory_conformance() {
+        conformance::run(MemoryContainer::new);
+    }
+
+    #[test]
+    fn sled_conformance() {
+        conformance::run(|| {
+            DB::ephemeral(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+        });
+    }
+
+    #[test]
+    fn sqlite_conformance() {
+        conformance::run(|| SqliteContainer::in_memory().unwrap());
+    }
+
+    #[test]
+    fn iter_any_container() {
+        let mut actts = Accounts::new(MemoryContainer::new());
+        for client in 1..=3 {
+            let tx = Transaction::<Deposit>::new(TransactionData {
+                t_type: TransactionType::Deposit,
+                client: ClientID(client),
+                tx: TxID::numeric(client),
+                amount: Some(Decimal::from(client)),
+                operator: None,
+                reason: None,
+            })
+            .unwrap();
+            actts.handle(tx).unwrap();
+        }
+
+        let mut data = actts.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        data.sort_by_key(|a| a.client);
+
+        assert_eq!(
+            data,
+            (1..=3)
+                .map(|client| AccountData {
+                    client: ClientID(client),
+                    available: Decimal::from(client),
+                    held: Decimal::from(0),
+                    total: Decimal::from(client),
+                    locked: false,
+                    state: State::Active,
+                    in_debt: false,
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn corrupted_record_is_an_error() {
+        let db = DB::new(sled::Config::new().temporary(true).open().unwrap()).unwrap();
+        db.db
+            .insert(client_key(&ClientID(1)), vec![1, 2, 3])
+            .unwrap();
+        let actts = Accounts::new(db);
+
+        let err = actts
+            .db
+            .get_account(&ClientID(1))
+            .expect_err("garbage is not an account");
+        assert!(matches!(err, ActionError::Corrupted(_)));
+        assert!(err.is_storage());
+
+        let mut iter = actts.iter();
+        assert!(matches!(iter.next(), Some(Err(ActionError::Corrupted(_)))));
+    }
+
+    #[test]
+    fn failed_commit_leaves_old_state() {
+        let path = std::env::temp_dir().join(format!("payments-atomic-{}", std::process::id()));
+        let deposit = Transaction::<Deposit>::new(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(1)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+        let dispute = || {
+            Transaction::<Dispute>::new(TransactionData {
+                t_type: TransactionType::Dispute,
+                client: ClientID(1),
+                tx: TxID::numeric(1),
+                amount: None,
+                operator: None,
+                reason: None,
+            })
+            .unwrap()
+        };
+
+        {
+            let mut actts = Accounts::new(DB::new(reopen(&path)).unwrap());
+            actts.handle(deposit.clone()).unwrap();
+
+            // the account gets written but the transaction does not
+            actts.db.fail_after_writes = Some(1);
+            let err = actts.handle(dispute()).expect_err("injected failure");
+            assert!(err.is_storage());
+        }
+
+        {
+            let mut actts = Accounts::new(DB::new(reopen(&path)).unwrap());
+            let acc = actts.db.get_account(&ClientID(1)).unwrap();
+            assert_eq!(acc.available(), Decimal::from(1));
+            assert_eq!(acc.held(), Decimal::from(0));
+            assert_eq!(
+                actts.db.transaction(&TxID::numeric(1)),
+                Ok(Some(Record::Deposit(deposit)))
+            );
+
+            actts.handle(dispute()).unwrap();
+        }
+
+        let actts = Accounts::new(DB::ephemeral(reopen(&path)).unwrap());
+        let acc = actts.db.get_account(&ClientID(1)).unwrap();
+        assert_eq!(acc.available(), Decimal::from(0));
+        assert_eq!(acc.held(), Decimal::from(1));
+        assert!(matches!(
+            actts.db.transaction(&TxID::numeric(1)),
+            Ok(Some(Record::Disputed(_)))
+        ));
+        drop(actts);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn group_commit_flushes_every_n_commits() {
+        let db = DB::ephemeral(sled::Config::new().temporary(true).open().unwrap())
+            .unwrap()
+            .with_durability(Durability::Group {
+                commits: 3,
+                interval: Duration::from_secs(3600),
+            });
+        let mut actts = Accounts::new(db);
+        let deposit = |tx| {
+            Transaction::<Deposit>::new(TransactionData {
+                t_type: TransactionType::Deposit,
+                client: ClientID(1),
+                tx: TxID::numeric(tx),
+                amount: Some(Decimal::from(1)),
+                operator: None,
+                reason: None,
+            })
+            .unwrap()
+        };
+
+        actts.handle(deposit(1)).unwrap();
+        actts.handle(deposit(2)).unwrap();
+        assert_eq!(actts.db.pending, 2);
+        // committed but not flushed yet
+        assert_eq!(
+            actts.db.get_account(&ClientID(1)).unwrap().total(),
+            Decimal::from(2)
+        );
+
+        actts.handle(deposit(3)).unwrap();
+        assert_eq!(actts.db.pending, 0);
+
+        actts.db.durability = Durability::EndOfBatch;
+        actts.handle(deposit(4)).unwrap();
+        actts.handle(deposit(5)).unwrap();
+        actts.handle(deposit(6)).unwrap();
+        assert_eq!(actts.db.pending, 3);
+        actts.flush().unwrap();
+        assert_eq!(actts.db.pending, 0);
+    }
+
+    #[test]
+    fn replay_rebuilds_the_accounts() {
+        let path = std::env::temp_dir().join(format!("payments-replay-{}", std::process::id()));
+
+        {
+            let mut actts = Accounts::new(DB::new(reopen(&path)).unwrap());
+            actts
+                .process(data(TransactionType::Deposit, 1, 1, Some(10)))
+                .unwrap();
+            actts
+                .process(data(TransactionType::Withdrawal, 1, 2, Some(3)))
+                .unwrap();
+        }
+
+        // the log carries on where it left off
+        let mut actts = Accounts::new(DB::ephemeral(reopen(&path)).unwrap());
+        actts
+            .process(data(TransactionType::Dispute, 1, 2, None))
+            .unwrap();
+        actts
+            .process(data(TransactionType::Chargeback, 1, 2, None))
+            .unwrap();
+        let seqs = actts.events().map(|e| e.unwrap().seq()).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+
+        let mut rebuilt = Accounts::new(MemoryContainer::new());
+        assert_eq!(rebuilt.replay(&actts.db).unwrap(), 4);
+        assert_eq!(
+            rebuilt.db.get_account(&ClientID(1)),
+            actts.db.get_account(&ClientID(1)),
+            "the same history gives the same balances"
+        );
+        assert_eq!(
+            rebuilt.db.transaction(&TxID::numeric(2)).unwrap(),
+            actts.db.transaction(&TxID::numeric(2)).unwrap()
+        );
+
+        // the history does not apply on top of itself
+        match rebuilt.replay(&actts.db) {
+            Err(ReplayError::Rejected { seq: 1, .. }) => {}
+            other => panic!("expected the first event to be rejected, got {:?}", other),
+        }
+
+        drop(actts);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn archive_outlives_the_run() {
+        let path = std::env::temp_dir().join(format!("payments-archive-{}", std::process::id()));
+        let deposits = ARCHIVE_BATCH as u64 * 2 + 10;
+
+        {
+            let mut actts = Accounts::new(DB::new(reopen(&path)).unwrap());
+            for tx in 1..=deposits {
+                actts
+                    .process(TransactionData {
+                        t_type: TransactionType::Deposit,
+                        client: ClientID(tx % 7),
+                        tx: TxID::numeric(tx),
+                        amount: Some(Decimal::from(1)),
+                        operator: None,
+                        reason: None,
+                    })
+                    .unwrap();
+            }
+            // more than a batch
+            assert_eq!(actts.archive(Archive::Keep(5)), Ok(deposits as usize - 5));
+        }
+
+        // the count and where the archive stopped are kept too
+        let actts = Accounts::new(DB::ephemeral(reopen(&path)).unwrap());
+        assert_eq!(actts.db.transactions().count(), 5);
+        assert_eq!(actts.db.transaction_count(), Ok(5));
+        assert_eq!(actts.db.archive_mark(), Ok(deposits - 5));
+        assert_eq!(actts.db.transaction(&TxID::numeric(1)), Ok(None));
+        assert!(matches!(
+            actts.transaction(&TxID::numeric(1)),
+            Ok(Some(Record::Deposit(_)))
+        ));
+        assert_eq!(
+            actts.history(ClientID(3)).count(),
+            (1..=deposits).filter(|tx| tx % 7 == 3).count()
+        );
+        assert_eq!(actts.db.events(0).count(), deposits as usize);
+
+        drop(actts);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        let from = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let to = std::env::temp_dir().join(format!("payments-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&to);
+        std::fs::create_dir_all(&to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+        to
+    }
+
+    // the fixtures hold the same history
+    // deposit 1 1 10, deposit 2 2 5, withdrawal 1 3 4, dispute 1 3,
+    // deposit 300 4 7.5, dispute 2 2, resolve 2 2, dispute 300 4, chargeback 300 4
+    fn check_fixture(db: &DB) {
+        let acc = db.get_account(&ClientID(1)).unwrap();
+        assert_eq!(acc.available(), Decimal::from(6));
+        assert_eq!(acc.held(), Decimal::from(4));
+        assert_eq!(acc.total(), Decimal::from(10));
+
+        let acc = db.get_account(&ClientID(2)).unwrap();
+        assert_eq!(acc.available(), Decimal::from(5));
+        assert!(acc.held().is_zero());
+
+        let acc = db.get_account(&ClientID(300)).unwrap();
+        assert!(acc.total().is_zero());
+        // which chargeback locked it was never kept
+        assert_eq!(
+            acc.status(),
+            &Status::Frozen(Cause::new("chargeback", None))
+        );
+
+        assert!(matches!(
+            db.transaction(&TxID::numeric(3)),
+            Ok(Some(Record::Disputed(_)))
+        ));
+        assert!(matches!(
+            db.transaction(&TxID::numeric(2)),
+            Ok(Some(Record::Resolved(_)))
+        ));
+        assert!(matches!(
+            db.transaction(&TxID::numeric(4)),
+            Ok(Some(Record::Chargedback(_)))
+        ));
+        assert_eq!(db.events(0).filter(|e| e.is_ok()).count(), 9);
+    }
+
+    fn versions(tree: &sled::Tree) -> Vec<u8> {
+        tree.iter()
+            .map(|res| schema::version(&res.unwrap().1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn store_without_schema_version_is_upgraded() {
+        let path = fixture("v0");
+        let mut db = DB::ephemeral(reopen(&path)).unwrap();
+
+        // the bare records are read as they are
+        assert!(versions(&db.db).iter().all(|v| *v == 0));
+        check_fixture(&db);
+
+        // 3 accounts, 4 transactions and 9 events
+        assert_eq!(db.migrate().unwrap(), 16);
+        assert_eq!(db.migrate().unwrap(), 0);
+        for tree in [&*db.db, &db.txs, &db.events].iter() {
+            assert!(versions(tree).iter().all(|v| *v == schema::CURRENT));
+        }
+        check_fixture(&db);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn store_with_narrow_ids_is_upgraded() {
+        let path = fixture("v1");
+        let mut db = DB::ephemeral(reopen(&path)).unwrap();
+        assert!(versions(&db.db).iter().all(|v| *v == 1));
+        check_fixture(&db);
+
+        assert_eq!(db.migrate().unwrap(), 16);
+        assert_eq!(db.migrate().unwrap(), 0);
+        check_fixture(&db);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn little_endian_keys_are_rewritten() {
+        // laid out the way version 1 of the store was
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("meta")
+            .unwrap()
+            .insert("schema", &[1])
+            .unwrap();
+        for id in [2u16, 256, 1].iter() {
+            let acc = schema::encode(&Account::new(ClientID(u64::from(*id)))).unwrap();
+            db.insert(id.to_le_bytes(), acc).unwrap();
+        }
+        let deposit = Record::Deposit(Transaction {
+            t: Deposit {
+                client: ClientID(256),
+                tx: TxID::numeric(513),
+                amount: Decimal::from(1),
+            },
+        });
+        db.open_tree("transactions")
+            .unwrap()
+            .insert(513u32.to_le_bytes(), schema::encode(&deposit).unwrap())
+            .unwrap();
+
+        let actts = Accounts::new(DB::new(db).unwrap());
+        let clients = actts
+            .iter()
+            .map(|acc| acc.unwrap().client)
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec![ClientID(1), ClientID(2), ClientID(256)]);
+        assert_eq!(actts.range(ClientID(2)..).count(), 2);
+        assert!(actts.db.get_account(&ClientID(256)).is_ok());
+        assert_eq!(actts.db.transaction(&TxID::numeric(513)), Ok(Some(deposit)));
+        assert_eq!(
+            actts.db.meta.get("schema").unwrap().as_deref(),
+            Some(&[schema::STORE][..])
+        );
+    }
+
+    #[test]
+    fn events_without_an_operator_are_upgraded() {
+        // laid out the way version 3 of the records was
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("meta")
+            .unwrap()
+            .insert("schema", &[schema::STORE])
+            .unwrap();
+        let data = TransactionData {
+            t_type: TransactionType::Deposit,
+            client: ClientID(1),
+            tx: TxID::numeric(1),
+            amount: Some(Decimal::from(2)),
+            operator: None,
+            reason: None,
+        };
+        let mut old = vec![3];
+        old.extend(
+            bincode::serialize(&(1u64, data.t_type, data.client, &data.tx, data.amount)).unwrap(),
+        );
+        db.open_tree("events")
+            .unwrap()
+            .insert(1u64.to_be_bytes(), old)
+            .unwrap();
+
+        let actts = Accounts::new(DB::new(db).unwrap());
+        let events = actts.events().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(events, vec![Event::new(1, data)]);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("meta")
+            .unwrap()
+            .insert("schema", &[schema::STORE + 1])
+            .unwrap();
+        assert!(DB::new(db).is_err());
     }
 }
@@ -0,0 +1,585 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+
+use super::{
+    Account, ActionError, Changeset, ClientID, Container, Event, Record, TransactionData, TxID,
+};
+
+/// A write-back cache in front of another [`Container`].
+///
+/// The most recently used accounts are kept in memory, up to `capacity`.
+/// Commits only change the cache, they are written back to the
+/// container behind it at a checkpoint, all of them in a single commit,
+/// so what gets written back is as atomic as each commit was.
+///
+/// A checkpoint happens when a changed account is evicted,
+/// when enough transactions piled up, see [`CachedContainer::with_checkpoint_every`],
+/// on [`CachedContainer::checkpoint`] and on [`Container::flush`].
+/// Whatever was not written back is lost in a crash.
+///
+/// Call [`Container::flush`] or [`CachedContainer::close`] before the cache
+/// is dropped, they are the only ones that report a failed write back.
+/// Dropping it still tries to write back what is left as a last resort,
+/// what it cannot write back is lost.
+pub struct CachedContainer<T: Container> {
+    inner: T,
+    capacity: usize,
+    checkpoint_every: usize,
+    // reads fill the cache too
+    cache: RefCell<Lru>,
+    // everything committed since the last checkpoint
+    // besides the accounts, those are the dirty entries
+    records: HashMap<TxID, Record>,
+    events: Vec<TransactionData>,
+    archived: HashMap<TxID, Record>,
+    archive_mark: Option<u64>,
+}
+
+struct Entry {
+    acc: Account,
+    // changed since the last checkpoint
+    dirty: bool,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<ClientID, Entry>,
+    // the clients by when they were last used
+    order: BTreeMap<u64, ClientID>,
+    clock: u64,
+}
+
+impl Lru {
+    fn get(&mut self, id: &ClientID) -> Option<Account> {
+        let used = self.tick();
+        let e = self.entries.get_mut(id)?;
+        self.order.remove(&e.used);
+        self.order.insert(used, *id);
+        e.used = used;
+        Some(e.acc.clone())
+    }
+
+    fn put(&mut self, acc: Account, dirty: bool) {
+        let used = self.tick();
+        let id = acc.client;
+        let dirty = match self.entries.remove(&id) {
+            Some(old) => {
+                self.order.remove(&old.used);
+                dirty || old.dirty
+            }
+            None => dirty,
+        };
+        self.order.insert(used, id);
+        self.entries.insert(id, Entry { acc, dirty, used });
+    }
+
+    // the least recently used client and if it is dirty
+    fn oldest(&self) -> Option<(ClientID, bool)> {
+        let id = self.order.values().next()?;
+        Some((*id, self.entries[id].dirty))
+    }
+
+    fn remove(&mut self, id: &ClientID) {
+        if let Some(e) = self.entries.remove(id) {
+            self.order.remove(&e.used);
+        }
+    }
+
+    fn dirty(&self) -> impl Iterator<Item = &Account> {
+        self.entries.values().filter(|e| e.dirty).map(|e| &e.acc)
+    }
+
+    fn is_dirty(&self, id: &ClientID) -> bool {
+        self.entries.get(id).is_some_and(|e| e.dirty)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+impl<T: Container> CachedContainer<T> {
+    pub fn new(inner: T, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity: capacity.max(1),
+            checkpoint_every: 10_000,
+            cache: RefCell::new(Lru::default()),
+            records: HashMap::new(),
+            events: Vec::new(),
+            archived: HashMap::new(),
+            archive_mark: None,
+        }
+    }
+
+    /// Bounds the transactions and events kept until the next checkpoint,
+    /// 10 000 unless set.
+    pub fn with_checkpoint_every(mut self, transactions: usize) -> Self {
+        self.checkpoint_every = transactions.max(1);
+        self
+    }
+
+    /// Writes everything committed since the last checkpoint
+    /// back to the container behind the cache, in a single commit.
+    /// Nothing is dropped from the cache if that fails.
+    pub fn checkpoint(&mut self) -> Result<(), ActionError> {
+        let mut changes = Changeset::new();
+        for acc in self.cache.get_mut().dirty() {
+            changes = changes.account(acc.clone());
+        }
+        for r in self.records.values() {
+            changes = changes.record(r.clone());
+        }
+        for data in self.events.iter() {
+            changes = changes.log(data.clone());
+        }
+        for r in self.archived.values() {
+            changes = changes.archive(r.clone());
+        }
+        if let Some(mark) = self.archive_mark {
+            changes = changes.archived_to(mark);
+        }
+        if changes == Changeset::new() {
+            return Ok(());
+        }
+
+        self.inner.commit(changes)?;
+
+        self.discard();
+        Ok(())
+    }
+
+    /// Writes back what is left, flushes the container behind the cache
+    /// and drops it. The cache is gone either way, whatever could not
+    /// be written back is lost and the failure returned.
+    pub fn close(mut self) -> Result<(), ActionError> {
+        let res = self.flush();
+        self.discard();
+        res
+    }
+
+    // anything committed that was not written back yet
+    fn is_pending(&self) -> bool {
+        self.cache.borrow().dirty().next().is_some()
+            || !self.records.is_empty()
+            || !self.events.is_empty()
+            || !self.archived.is_empty()
+            || self.archive_mark.is_some()
+    }
+
+    fn discard(&mut self) {
+        for e in self.cache.get_mut().entries.values_mut() {
+            e.dirty = false;
+        }
+        self.records.clear();
+        self.events.clear();
+        self.archived.clear();
+        self.archive_mark = None;
+    }
+
+    // makes room for more accounts
+    // a dirty account cannot leave the cache before it is written back
+    fn evict(&mut self, room: usize) -> Result<(), ActionError> {
+        while self.cache.get_mut().entries.len() + room > self.capacity {
+            let (id, dirty) = match self.cache.get_mut().oldest() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if dirty {
+                self.checkpoint()?;
+            }
+            self.cache.get_mut().remove(&id);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Container> Container for CachedContainer<T> {
+    fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
+        if let Some(acc) = self.cache.borrow_mut().get(id) {
+            return Ok(acc);
+        }
+
+        let acc = self.inner.get_account(id)?;
+        // reads cannot write back, so they only evict clean accounts
+        let mut cache = self.cache.borrow_mut();
+        if cache.entries.len() >= self.capacity {
+            match cache.oldest() {
+                Some((oldest, false)) => cache.remove(&oldest),
+                _ => return Ok(acc),
+            }
+        }
+        cache.put(acc.clone(), false);
+        Ok(acc)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        self.accounts_range((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn accounts_range(
+        &self,
+        clients: (Bound<ClientID>, Bound<ClientID>),
+    ) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        // the dirty accounts replace the stored ones
+        // and both are merged in the order of the clients
+        let mut dirty = self
+            .cache
+            .borrow()
+            .dirty()
+            .filter(|acc| clients.contains(&acc.client))
+            .cloned()
+            .collect::<Vec<_>>();
+        dirty.sort_unstable_by_key(Account::client);
+        let mut dirty = dirty.into_iter().peekable();
+
+        let mut stored = self
+            .inner
+            .accounts_range(clients)
+            .filter(move |acc| match acc {
+                Ok(acc) => !self.cache.borrow().is_dirty(&acc.client),
+                Err(_) => true,
+            })
+            .peekable();
+
+        Box::new(std::iter::from_fn(move || {
+            let dirty_first = match (stored.peek(), dirty.peek()) {
+                (Some(Ok(s)), Some(d)) => d.client < s.client,
+                (Some(_), _) => false,
+                (None, _) => true,
+            };
+            if dirty_first {
+                dirty.next().map(Ok)
+            } else {
+                stored.next()
+            }
+        }))
+    }
+
+    fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        if self.archived.contains_key(tx) {
+            return Ok(None);
+        }
+        match self.records.get(tx) {
+            Some(r) => Ok(Some(r.clone())),
+            None => self.inner.transaction(tx),
+        }
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        let stored = self.inner.transactions().filter(move |r| match r {
+            Ok(r) => !self.records.contains_key(r.tx()) && !self.archived.contains_key(r.tx()),
+            Err(_) => true,
+        });
+        Box::new(stored.chain(self.records.values().cloned().map(Ok)))
+    }
+
+    fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        match self.archived.get(tx) {
+            Some(r) => Ok(Some(r.clone())),
+            None => self.inner.archived(tx),
+        }
+    }
+
+    fn archived_transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        let stored = self.inner.archived_transactions();
+        Box::new(stored.chain(self.archived.values().cloned().map(Ok)))
+    }
+
+    fn commit(&mut self, changes: Changeset) -> Result<(), ActionError> {
+        // the room is made before anything changes
+        // so a write back never carries half of this changeset
+        let cache = self.cache.get_mut();
+        let new = changes
+            .accounts
+            .iter()
+            .filter(|acc| !cache.entries.contains_key(&acc.client))
+            .count();
+        // more accounts than the cache holds, like an imported snapshot,
+        // go straight through, after what piled up so the order is kept
+        if new > self.capacity {
+            self.checkpoint()?;
+            for acc in changes.accounts() {
+                self.cache.get_mut().remove(&acc.client);
+            }
+            return self.inner.commit(changes);
+        }
+        if new > 0 {
+            self.evict(new)?;
+        }
+
+        for acc in changes.accounts {
+            self.cache.get_mut().put(acc, true);
+        }
+        for r in changes.records {
+            self.records.insert(r.tx().clone(), r);
+        }
+        self.events.extend(changes.events);
+        for r in changes.archived {
+            self.records.remove(r.tx());
+            self.archived.insert(r.tx().clone(), r);
+        }
+        if let Some(mark) = changes.archive_mark {
+            self.archive_mark = Some(mark);
+        }
+
+        // the transactions are not bounded by the accounts
+        let piled = self.records.len() + self.archived.len();
+        if piled.max(self.events.len()) >= self.checkpoint_every {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // The events that are not written back yet are numbered
+    // after the last one that is
+    fn events(&self, from: u64) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_> {
+        if self.events.is_empty() {
+            return self.inner.events(from);
+        }
+
+        let last = match self.inner.last_seq() {
+            Ok(last) => last,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let pending = self
+            .events
+            .iter()
+            .zip(last + 1..)
+            .filter(move |(_, seq)| *seq >= from)
+            .map(|(data, seq)| Ok(Event::new(seq, data.clone())));
+        Box::new(self.inner.events(from).chain(pending))
+    }
+
+    fn last_seq(&self) -> Result<u64, ActionError> {
+        Ok(self.inner.last_seq()? + self.events.len() as u64)
+    }
+
+    // only the transactions piled up since the last checkpoint
+    // are looked up behind the cache
+    fn transaction_count(&self) -> Result<u64, ActionError> {
+        let mut count = self.inner.transaction_count()?;
+        for tx in self.records.keys() {
+            if self.inner.transaction(tx)?.is_none() {
+                count += 1;
+            }
+        }
+        for tx in self.archived.keys() {
+            if self.inner.transaction(tx)?.is_some() {
+                count -= 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn archive_mark(&self) -> Result<u64, ActionError> {
+        match self.archive_mark {
+            Some(mark) => Ok(mark),
+            None => self.inner.archive_mark(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), ActionError> {
+        self.checkpoint()?;
+        self.inner.flush()
+    }
+}
+
+// the last resort for what was not flushed
+// a failure cannot be returned from here, see CachedContainer::close
+impl<T: Container> Drop for CachedContainer<T> {
+    fn drop(&mut self) {
+        if !self.is_pending() {
+            return;
+        }
+        let res = self.flush();
+        debug_assert!(
+            res.is_ok() || std::thread::panicking(),
+            "the cache was dropped and could not be written back: {:?}",
+            res
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::payments::conformance::{self, data};
+    use crate::payments::{Accounts, MemoryContainer, TransactionType};
+
+    // remembers every commit that reaches it
+    #[derive(Default)]
+    struct Recording {
+        inner: MemoryContainer,
+        commits: Vec<Changeset>,
+    }
+
+    impl Container for Recording {
+        fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
+            self.inner.get_account(id)
+        }
+
+        fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+            self.inner.accounts()
+        }
+
+        fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+            self.inner.transaction(tx)
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+            self.inner.transactions()
+        }
+
+        fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+            self.inner.archived(tx)
+        }
+
+        fn archived_transactions(
+            &self,
+        ) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+            self.inner.archived_transactions()
+        }
+
+        fn commit(&mut self, changes: Changeset) -> Result<(), ActionError> {
+            self.commits.push(changes.clone());
+            self.inner.commit(changes)
+        }
+
+        fn events(&self, from: u64) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_> {
+            self.inner.events(from)
+        }
+    }
+
+    #[test]
+    fn cached_conformance() {
+        conformance::run(|| CachedContainer::new(MemoryContainer::new(), 100));
+        conformance::run(|| {
+            CachedContainer::new(MemoryContainer::new(), 1).with_checkpoint_every(2)
+        });
+    }
+
+    #[test]
+    fn evicting_a_dirty_account_writes_everything_back() {
+        let mut accts = Accounts::new(CachedContainer::new(Recording::default(), 2));
+        accts
+            .process(data(TransactionType::Deposit, 1, 1, Some(1)))
+            .unwrap();
+        accts
+            .process(data(TransactionType::Deposit, 2, 2, Some(1)))
+            .unwrap();
+        accts
+            .process(data(TransactionType::Deposit, 1, 3, Some(1)))
+            .unwrap();
+        assert!(accts.db.inner.commits.is_empty(), "nothing is written yet");
+
+        // client 2 is the least recently used one
+        accts
+            .process(data(TransactionType::Deposit, 3, 4, Some(1)))
+            .unwrap();
+        let commits = &accts.db.inner.commits;
+        assert_eq!(commits.len(), 1, "one atomic write back");
+        let mut clients = commits[0]
+            .accounts()
+            .iter()
+            .map(Account::client)
+            .collect::<Vec<_>>();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![ClientID(1), ClientID(2)]);
+        assert_eq!(commits[0].records().len(), 3);
+        assert_eq!(commits[0].events().len(), 3);
+
+        assert_eq!(
+            accts.db.inner.get_account(&ClientID(1)).unwrap().total(),
+            Decimal::from(2)
+        );
+        assert_eq!(
+            accts.db.inner.get_account(&ClientID(3)),
+            Err(ActionError::InvalidClientID)
+        );
+
+        accts.flush().unwrap();
+        assert_eq!(accts.db.inner.commits.len(), 2);
+        assert_eq!(
+            accts.db.inner.get_account(&ClientID(3)).unwrap().total(),
+            Decimal::from(1)
+        );
+        assert_eq!(accts.db.inner.events(0).count(), 4);
+    }
+
+    #[test]
+    fn a_commit_bigger_than_the_cache_goes_through() {
+        let mut cache = CachedContainer::new(Recording::default(), 2);
+        cache
+            .save_account(Account::new(ClientID(1)))
+            .expect("saving an account");
+
+        let mut changes = Changeset::new();
+        for client in 2..=4 {
+            changes = changes.account(Account::new(ClientID(client)));
+        }
+        cache.commit(changes).unwrap();
+        assert!(cache.cache.get_mut().entries.len() <= 2);
+        let commits = &cache.inner.commits;
+        assert_eq!(commits.len(), 2, "what piled up is written back first");
+        assert_eq!(commits[0].accounts().len(), 1);
+        assert_eq!(commits[1].accounts().len(), 3);
+        assert_eq!(cache.accounts().count(), 4);
+        cache.close().unwrap();
+    }
+
+    #[test]
+    fn close_reports_a_failed_write_back() {
+        struct Failing(MemoryContainer);
+
+        impl Container for Failing {
+            fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
+                self.0.get_account(id)
+            }
+
+            fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+                self.0.accounts()
+            }
+
+            fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+                self.0.transaction(tx)
+            }
+
+            fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+                self.0.transactions()
+            }
+
+            fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+                self.0.archived(tx)
+            }
+
+            fn archived_transactions(
+                &self,
+            ) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+                self.0.archived_transactions()
+            }
+
+            fn commit(&mut self, _: Changeset) -> Result<(), ActionError> {
+                Err(ActionError::storage("the disk is full"))
+            }
+
+            fn events(
+                &self,
+                from: u64,
+            ) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_> {
+                self.0.events(from)
+            }
+        }
+
+        let mut accts = Accounts::new(CachedContainer::new(Failing(MemoryContainer::new()), 10));
+        accts
+            .process(data(TransactionType::Deposit, 1, 1, Some(1)))
+            .unwrap();
+        assert!(matches!(accts.db.close(), Err(ActionError::Storage(_))));
+    }
+}
@@ -0,0 +1,530 @@
+//! Behavioral checks for [`Container`] implementations.
+//!
+//! Every storage backend is expected to behave the same way.
+//! Call [`run`] from a test of your own backend,
+//! it panics with a description of the first check that fails.
+//!
+//! ```no_run
+//! use payments::{conformance, MemoryContainer};
+//!
+//! conformance::run(MemoryContainer::new);
+//! ```
+
+use std::ops::Bound;
+
+use rust_decimal::Decimal;
+
+use super::{
+    Account, Accounts, ActionError, Archive, Cause, Changeset, Chargeback, Chargedback, ClientID,
+    Container, Deposit, Dispute, Disputed, Record, Resolve, Status, Transaction, TransactionData,
+    TransactionType, TxID, Withdrawal,
+};
+
+/// Runs every check, each one against a fresh container from `new`.
+pub fn run<T, F>(mut new: F)
+where
+    T: Container,
+    F: FnMut() -> T,
+{
+    unknown_account(new());
+    save_and_get(new());
+    accounts_are_enumerated(new());
+    transaction_lookup(new());
+    transaction_ids_are_global(new());
+    every_id_kind(new());
+    commit_stores_everything(new());
+    balances(new());
+    events_are_logged(new());
+    settled_transactions_are_archived(new());
+}
+
+fn cid(id: u64) -> ClientID {
+    ClientID::new(id)
+}
+
+fn txid(id: u64) -> TxID {
+    TxID::numeric(id)
+}
+
+// shared with the tests of the crate
+pub(crate) fn data(
+    t_type: TransactionType,
+    client: u64,
+    tx: u64,
+    amount: Option<i64>,
+) -> TransactionData {
+    TransactionData {
+        t_type,
+        client: cid(client),
+        tx: txid(tx),
+        amount: amount.map(Decimal::from),
+        operator: None,
+        reason: None,
+    }
+}
+
+fn deposit(client: u64, tx: u64, amount: i64) -> Transaction<Deposit> {
+    Transaction::<Deposit>::new(data(TransactionType::Deposit, client, tx, Some(amount))).unwrap()
+}
+
+fn unknown_account<T: Container>(c: T) {
+    assert_eq!(
+        c.get_account(&cid(1)),
+        Err(ActionError::InvalidClientID),
+        "an unknown client has no account"
+    );
+
+    let acc = c
+        .get_or_create(&cid(1))
+        .expect("get_or_create on an unknown client");
+    assert_eq!(acc.client(), cid(1));
+    assert!(acc.total().is_zero(), "a new account is empty");
+    assert_eq!(
+        c.get_account(&cid(1)),
+        Err(ActionError::InvalidClientID),
+        "get_or_create does not store the account"
+    );
+}
+
+fn save_and_get<T: Container>(mut c: T) {
+    let mut acc = c.get_or_create(&cid(1)).unwrap();
+    c.save_account(acc.clone()).expect("saving an account");
+    assert_eq!(c.get_account(&cid(1)).as_ref(), Ok(&acc));
+
+    acc.available += Decimal::from(1);
+    acc.total += Decimal::from(1);
+    c.save_account(acc.clone()).expect("overwriting an account");
+    assert_eq!(
+        c.get_account(&cid(1)).as_ref(),
+        Ok(&acc),
+        "saving replaces the previous version"
+    );
+
+    for status in [
+        Status::Suspended(Cause::new("under review", Some(txid(7)))),
+        Status::Closed(Cause::new("", None)),
+        Status::Active,
+    ]
+    .iter()
+    {
+        acc.status = status.clone();
+        c.save_account(acc.clone()).unwrap();
+        assert_eq!(
+            c.get_account(&cid(1)).map(|acc| acc.status().clone()),
+            Ok(status.clone()),
+            "the status is kept with its cause"
+        );
+    }
+}
+
+fn accounts_are_enumerated<T: Container>(mut c: T) {
+    assert_eq!(c.accounts().count(), 0, "a new container is empty");
+
+    for id in [300, 2, 256, 1].iter() {
+        let acc = c.get_or_create(&cid(*id)).unwrap();
+        c.save_account(acc.clone()).unwrap();
+        c.save_account(acc).unwrap();
+    }
+
+    let ids = |accounts: Box<dyn Iterator<Item = Result<Account, ActionError>> + '_>| {
+        accounts
+            .map(|acc| acc.expect("reading an account").client().get())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        ids(c.accounts()),
+        vec![1, 2, 256, 300],
+        "every account is listed once, in the order of the clients"
+    );
+    assert_eq!(
+        ids(c.accounts_range((Bound::Included(cid(2)), Bound::Excluded(cid(300))))),
+        vec![2, 256]
+    );
+    assert_eq!(
+        ids(c.accounts_range((Bound::Excluded(cid(2)), Bound::Unbounded))),
+        vec![256, 300]
+    );
+    assert_eq!(
+        ids(c.accounts_range((Bound::Included(cid(3)), Bound::Included(cid(255))))),
+        Vec::<u64>::new()
+    );
+}
+
+fn transaction_lookup<T: Container>(c: T) {
+    let mut accts = Accounts::new(c);
+    accts.handle(deposit(1, 10, 5)).unwrap();
+    accts.handle(deposit(2, 20, 5)).unwrap();
+
+    assert_eq!(accts.db.transaction(&txid(30)), Ok(None));
+    let mut txs = accts
+        .db
+        .transactions()
+        .map(|r| r.expect("reading a transaction").tx().clone())
+        .collect::<Vec<_>>();
+    txs.sort_unstable();
+    assert_eq!(
+        txs,
+        vec![txid(10), txid(20)],
+        "every transaction is listed once"
+    );
+    match accts.db.transaction(&txid(20)) {
+        Ok(Some(r @ Record::Deposit(_))) => assert_eq!(r.client(), cid(2)),
+        other => panic!("expected the deposit of client 2, got {:?}", other),
+    }
+
+    let dispute = Transaction::<Dispute>::new(data(TransactionType::Dispute, 1, 10, None)).unwrap();
+    accts.handle(dispute).unwrap();
+    match accts.db.transaction(&txid(10)) {
+        Ok(Some(Record::Disputed(_))) => {}
+        other => panic!("expected a disputed deposit, got {:?}", other),
+    }
+}
+
+fn transaction_ids_are_global<T: Container>(c: T) {
+    let mut accts = Accounts::new(c);
+    accts.handle(deposit(1, 1, 5)).unwrap();
+    accts.handle(deposit(2, 2, 5)).unwrap();
+
+    assert_eq!(
+        accts.handle(deposit(2, 1, 5)),
+        Err(ActionError::InvalidTxID),
+        "another client cannot reuse a transaction id"
+    );
+
+    let dispute = Transaction::<Dispute>::new(data(TransactionType::Dispute, 2, 1, None)).unwrap();
+    assert_eq!(
+        accts.handle(dispute),
+        Err(ActionError::ClientMismatch),
+        "a client cannot dispute the transaction of another client"
+    );
+
+    let dispute = Transaction::<Dispute>::new(data(TransactionType::Dispute, 1, 1, None)).unwrap();
+    accts.handle(dispute).unwrap();
+    assert_eq!(
+        accts.handle(deposit(1, 1, 5)),
+        Err(ActionError::InvalidTxID),
+        "a disputed transaction id stays taken"
+    );
+}
+
+fn every_id_kind<T: Container>(c: T) {
+    // the largest ids, past what a signed 64 bit integer holds
+    let big = cid(u64::MAX);
+    let uuid = "9b2f6c1e-3d4a-4e8b-a1c2-7f0e5d6b8a90"
+        .parse::<TxID>()
+        .unwrap();
+    let mut accts = Accounts::new(c);
+    accts
+        .process(TransactionData {
+            t_type: TransactionType::Deposit,
+            client: big,
+            tx: uuid.clone(),
+            amount: Some(Decimal::from(5)),
+            operator: None,
+            reason: None,
+        })
+        .unwrap();
+    accts.handle(deposit(1, u64::MAX, 5)).unwrap();
+    accts.handle(deposit(1, i64::MAX as u64 + 1, 5)).unwrap();
+
+    match accts.db.transaction(&uuid) {
+        Ok(Some(r @ Record::Deposit(_))) => {
+            assert_eq!(r.client(), big);
+            assert_eq!(r.tx(), &uuid, "an opaque id is kept as it is");
+        }
+        other => panic!("expected the deposit of the opaque id, got {:?}", other),
+    }
+    for tx in [u64::MAX, i64::MAX as u64 + 1] {
+        assert!(matches!(
+            accts.db.transaction(&txid(tx)),
+            Ok(Some(Record::Deposit(_)))
+        ));
+    }
+    assert_eq!(accts.db.transaction(&txid(i64::MAX as u64)), Ok(None));
+    assert_eq!(
+        accts
+            .db
+            .transaction(&"9B2F6C1E-3D4A-4E8B-A1C2-7F0E5D6B8A90".parse().unwrap()),
+        Ok(None),
+        "opaque ids are compared exactly"
+    );
+    assert_eq!(
+        accts.db.get_account(&big).unwrap().total(),
+        Decimal::from(5)
+    );
+
+    let dispute = TransactionData {
+        t_type: TransactionType::Dispute,
+        client: big,
+        tx: uuid.clone(),
+        amount: None,
+        operator: None,
+        reason: None,
+    };
+    accts.process(dispute).unwrap();
+    assert!(matches!(
+        accts.db.transaction(&uuid),
+        Ok(Some(Record::Disputed(_)))
+    ));
+    let clients = accts
+        .db
+        .accounts()
+        .map(|acc| acc.expect("reading an account").client())
+        .collect::<Vec<_>>();
+    assert_eq!(clients, vec![cid(1), big]);
+}
+
+fn commit_stores_everything<T: Container>(mut c: T) {
+    let a = c.get_or_create(&cid(1)).unwrap();
+    let b = c.get_or_create(&cid(2)).unwrap();
+    let r = Record::Deposit(deposit(1, 1, 5));
+
+    c.commit(
+        Changeset::new()
+            .account(a.clone())
+            .account(b.clone())
+            .record(r.clone()),
+    )
+    .expect("committing a changeset");
+
+    assert_eq!(c.get_account(&cid(1)), Ok(a));
+    assert_eq!(c.get_account(&cid(2)), Ok(b));
+    assert_eq!(c.transaction(&txid(1)), Ok(Some(r)));
+
+    // the output rounds to four places, the storage does not
+    let precise = Decimal::new(123_456_789, 8);
+    let acc = Account::from_parts(
+        cid(3),
+        precise,
+        Decimal::new(1, 6),
+        precise + Decimal::new(1, 6),
+        Status::Frozen(Cause::new("chargeback", Some(txid(2)))),
+    );
+    let r = Record::Chargedback(Chargedback::new(Disputed::Deposit(
+        Transaction::<Deposit>::from_parts(cid(3), txid(2), precise),
+    )));
+    let event = TransactionData::from_parts(
+        TransactionType::Deposit,
+        cid(3),
+        txid(2),
+        Some(precise),
+        None,
+        None,
+    );
+    c.commit(
+        Changeset::new()
+            .account(acc.clone())
+            .record(r.clone())
+            .log(event.clone()),
+    )
+    .unwrap();
+    assert_eq!(
+        c.get_account(&cid(3)),
+        Ok(acc),
+        "the amounts are stored with every decimal place"
+    );
+    assert_eq!(c.transaction(&txid(2)), Ok(Some(r)));
+    let logged = c.events(0).last().expect("the logged event").unwrap();
+    assert_eq!(logged.data(), &event);
+}
+
+fn balances<T: Container>(c: T) {
+    let mut accts = Accounts::new(c);
+    accts.handle(deposit(1, 1, 10)).unwrap();
+
+    let withdrawal =
+        Transaction::<Withdrawal>::new(data(TransactionType::Withdrawal, 1, 2, Some(4))).unwrap();
+    accts.handle(withdrawal).unwrap();
+
+    let dispute = Transaction::<Dispute>::new(data(TransactionType::Dispute, 1, 2, None)).unwrap();
+    accts.handle(dispute).unwrap();
+
+    let acc = accts.db.get_account(&cid(1)).unwrap();
+    assert_eq!(acc.available(), Decimal::from(6));
+    assert_eq!(acc.held(), Decimal::from(4));
+    assert_eq!(acc.total(), Decimal::from(10));
+
+    let chargeback =
+        Transaction::<Chargeback>::new(data(TransactionType::Chargeback, 1, 2, None)).unwrap();
+    accts.handle(chargeback).unwrap();
+
+    let acc = accts.db.get_account(&cid(1)).unwrap();
+    assert_eq!(acc.available(), Decimal::from(10));
+    assert!(acc.held().is_zero());
+    assert_eq!(acc.total(), Decimal::from(10));
+    assert!(
+        !acc.locked(),
+        "charging back a withdrawal leaves the account as it is"
+    );
+
+    for t_type in [TransactionType::Dispute, TransactionType::Chargeback] {
+        accts.process(data(t_type, 1, 1, None)).unwrap();
+    }
+    let acc = accts.db.get_account(&cid(1)).unwrap();
+    assert!(acc.total().is_zero());
+    assert!(acc.locked(), "charging back a deposit locks the account");
+    assert_eq!(
+        acc.status(),
+        &Status::Frozen(Cause::new("chargeback", Some(txid(1))))
+    );
+
+    assert_eq!(
+        accts.handle(deposit(1, 3, 1)),
+        Err(ActionError::AccountLocked)
+    );
+}
+
+fn events_are_logged<T: Container>(c: T) {
+    let mut accts = Accounts::new(c).with_admin(true);
+    assert_eq!(accts.events().count(), 0, "a new container has no events");
+    assert_eq!(accts.db.last_seq(), Ok(0));
+
+    accts
+        .process(data(TransactionType::Deposit, 1, 1, Some(10)))
+        .unwrap();
+    accts
+        .process(data(TransactionType::Withdrawal, 1, 2, Some(4)))
+        .unwrap();
+    accts
+        .process(data(TransactionType::Withdrawal, 1, 3, Some(100)))
+        .expect_err("insufficient funds");
+    accts
+        .process(data(TransactionType::Dispute, 1, 2, None))
+        .unwrap();
+    let freeze = TransactionData {
+        operator: Some("alice".to_string()),
+        reason: Some("fraud review".to_string()),
+        ..data(TransactionType::Freeze, 1, 4, None)
+    };
+    accts.process(freeze.clone()).unwrap();
+
+    let events = accts
+        .events()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("reading the events");
+    let types = events
+        .iter()
+        .map(|e| e.data().tx_type())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        vec![
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Freeze
+        ],
+        "only the applied actions are logged, in order"
+    );
+    assert!(
+        events.windows(2).all(|w| w[0].seq() < w[1].seq()),
+        "sequence numbers increase"
+    );
+    assert_eq!(
+        accts.db.last_seq(),
+        Ok(events[3].seq()),
+        "the last sequence number is the one of the last event"
+    );
+    assert_eq!(
+        events[2].data(),
+        &data(TransactionType::Dispute, 1, 2, None)
+    );
+    assert_eq!(
+        events[3].data(),
+        &freeze,
+        "the operator and the reason are logged"
+    );
+    match accts.db.transaction(&txid(4)) {
+        Ok(Some(Record::Ordered(o))) => assert_eq!(o.t_type(), TransactionType::Freeze),
+        other => panic!("the freeze is not stored: {:?}", other),
+    }
+
+    let from = accts
+        .db
+        .events(events[1].seq())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        from,
+        events[1..].to_vec(),
+        "events can be read from a sequence number"
+    );
+}
+
+fn settled_transactions_are_archived<T: Container>(c: T) {
+    let mut accts = Accounts::new(c);
+    accts.handle(deposit(1, 1, 10)).unwrap();
+    accts.handle(deposit(1, 2, 10)).unwrap();
+    accts.handle(deposit(2, 3, 10)).unwrap();
+    let dispute = Transaction::<Dispute>::new(data(TransactionType::Dispute, 1, 2, None)).unwrap();
+    accts.handle(dispute).unwrap();
+
+    assert_eq!(
+        accts.archive(Archive::After(2)),
+        Ok(1),
+        "only what is settled and old enough is archived"
+    );
+    assert_eq!(accts.db.transaction(&txid(1)), Ok(None));
+    match accts.db.archived(&txid(1)) {
+        Ok(Some(Record::Deposit(_))) => {}
+        other => panic!("expected the archived deposit, got {:?}", other),
+    }
+    assert_eq!(accts.db.archived(&txid(2)), Ok(None));
+    assert_eq!(accts.db.transactions().count(), 2);
+    assert_eq!(accts.db.transaction_count(), Ok(2));
+    assert_eq!(accts.db.archived_transactions().count(), 1);
+
+    assert_eq!(
+        accts.handle(deposit(1, 1, 5)),
+        Err(ActionError::InvalidTxID),
+        "an archived transaction id stays taken"
+    );
+    let dispute = Transaction::<Dispute>::new(data(TransactionType::Dispute, 1, 1, None)).unwrap();
+    assert_eq!(
+        accts.handle(dispute),
+        Err(ActionError::InvalidTxID),
+        "an archived transaction cannot be disputed"
+    );
+
+    assert_eq!(
+        accts.archive(Archive::Keep(0)),
+        Ok(1),
+        "the open dispute stays"
+    );
+    assert_eq!(accts.db.transaction_count(), Ok(1));
+    let mark = accts.db.archive_mark().unwrap();
+    assert!(
+        mark == 0 || mark == accts.db.last_seq().unwrap(),
+        "the archive went through the whole log, got {}",
+        mark
+    );
+    assert_eq!(accts.archive(Archive::Keep(0)), Ok(0));
+    let mut history = accts
+        .history(cid(1))
+        .map(|r| r.expect("reading the history").tx().clone())
+        .collect::<Vec<_>>();
+    history.sort_unstable();
+    assert_eq!(
+        history,
+        vec![txid(1), txid(2)],
+        "the history covers the archive"
+    );
+    assert!(matches!(
+        accts.transaction(&txid(3)),
+        Ok(Some(Record::Deposit(_)))
+    ));
+    assert_eq!(
+        accts.db.get_account(&cid(1)).unwrap().total(),
+        Decimal::from(20),
+        "archiving leaves the balances alone"
+    );
+
+    let resolve = Transaction::<Resolve>::new(data(TransactionType::Resolve, 1, 2, None)).unwrap();
+    accts.handle(resolve).unwrap();
+    assert_eq!(
+        accts.archive(Archive::Keep(0)),
+        Ok(1),
+        "a settled dispute is archived once it is resolved"
+    );
+    assert_eq!(accts.db.transaction_count(), Ok(0));
+}
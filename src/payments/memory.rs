@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use super::{Account, ActionError, Changeset, ClientID, Container, Event, Record, TxID};
+
+// Keeps everything in memory
+// nothing touches the disk and everything is gone
+// once the container is dropped
+#[derive(Default)]
+pub struct MemoryContainer {
+    // ordered, the accounts are listed by client
+    data: BTreeMap<ClientID, Account>,
+    // every transaction keyed by its id
+    txs: HashMap<TxID, Record>,
+    // the sequence number of an event is its position plus one
+    events: Vec<Event>,
+    archive: HashMap<TxID, Record>,
+    archive_mark: u64,
+}
+
+impl MemoryContainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Container for MemoryContainer {
+    fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
+        self.data
+            .get(id)
+            .ok_or(ActionError::InvalidClientID)
+            .cloned()
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        Box::new(self.data.values().cloned().map(Ok))
+    }
+
+    fn accounts_range(
+        &self,
+        clients: (Bound<ClientID>, Bound<ClientID>),
+    ) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        Box::new(self.data.range(clients).map(|(_, acc)| Ok(acc.clone())))
+    }
+
+    fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        Ok(self.txs.get(tx).cloned())
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        Box::new(self.txs.values().cloned().map(Ok))
+    }
+
+    fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        Ok(self.archive.get(tx).cloned())
+    }
+
+    fn archived_transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        Box::new(self.archive.values().cloned().map(Ok))
+    }
+
+    // nothing here can fail half way
+    fn commit(&mut self, changes: Changeset) -> Result<(), ActionError> {
+        for acc in changes.accounts() {
+            self.data.insert(acc.client, acc.clone());
+        }
+        for r in changes.records() {
+            self.txs.insert(r.tx().clone(), r.clone());
+        }
+        for data in changes.events() {
+            let seq = self.events.len() as u64 + 1;
+            self.events.push(Event::new(seq, data.clone()));
+        }
+        for r in changes.archived() {
+            self.txs.remove(r.tx());
+            self.archive.insert(r.tx().clone(), r.clone());
+        }
+        if let Some(mark) = changes.archive_mark() {
+            self.archive_mark = mark;
+        }
+        Ok(())
+    }
+
+    fn events(&self, from: u64) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_> {
+        let skip = from.saturating_sub(1) as usize;
+        Box::new(self.events.iter().skip(skip).cloned().map(Ok))
+    }
+
+    fn last_seq(&self) -> Result<u64, ActionError> {
+        Ok(self.events.len() as u64)
+    }
+
+    fn transaction_count(&self) -> Result<u64, ActionError> {
+        Ok(self.txs.len() as u64)
+    }
+
+    fn archive_mark(&self) -> Result<u64, ActionError> {
+        Ok(self.archive_mark)
+    }
+}
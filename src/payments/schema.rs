@@ -0,0 +1,447 @@
+// Everything the DB stores is wrapped in an envelope,
+// a single byte with the version of the schema
+// the record was written in, followed by the record itself.
+//
+// Records are always written in the current version.
+// Older ones are upgraded when they are read,
+// DB::migrate upgrades all of them in one go.
+//
+// Stores written before the envelope existed
+// hold bare records, they are version 0.
+// They are wrapped as such the first time the store is opened.
+//
+// Versions 0 and 1 of the records had 16 bit client ids
+// and 32 bit transaction ids, version 2 widened both.
+// Up to version 2 an account was either locked or not,
+// version 3 keeps the state it is in and what caused it.
+// Version 4 logs the operator and the reason with the events.
+//
+// The store as a whole has a version of its own, for the layout
+// of the trees. Version 1 keyed the accounts and the transactions
+// by their little endian ids, which do not sort as numbers.
+// Version 2 keyed them by the narrow ids in big endian.
+// The keys of both are rewritten the first time the store is opened.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::TransactionError;
+use sled::Transactional;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use super::{
+    client_key, tx_key, Account, ActionError, Cause, Chargedback, ClientID, Deposit, Disputed,
+    Event, Record, Resolved, Status, Transaction, TransactionData, TransactionType, TxID,
+    Withdrawal,
+};
+
+/// The schema version every record is written in.
+pub const CURRENT: u8 = 4;
+
+// the layout of the trees, kept in the meta tree under SCHEMA_KEY
+// nothing older than it opens the store
+pub(crate) const STORE: u8 = 3;
+const SCHEMA_KEY: &[u8] = b"schema";
+
+// A record that can be read back from any version it was ever written in
+pub(crate) trait Versioned: Serialize + DeserializeOwned {
+    // decodes a record written in an older version
+    // and brings it up to the current one
+    fn upgrade(version: u8, body: &[u8]) -> bincode::Result<Self>;
+}
+
+// an account as version 2 laid it out
+#[derive(Deserialize)]
+pub(crate) struct LockedAccount {
+    client: ClientID,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl From<LockedAccount> for Account {
+    fn from(acc: LockedAccount) -> Self {
+        // only a chargeback ever locked an account
+        let status = if acc.locked {
+            Status::Frozen(Cause::new("chargeback", None))
+        } else {
+            Status::Active
+        };
+        Account {
+            client: acc.client,
+            available: acc.available,
+            held: acc.held,
+            total: acc.total,
+            status,
+        }
+    }
+}
+
+// the records as versions 0 and 1 laid them out
+// 0 is the same layout as 1, without the envelope
+pub(crate) mod narrow {
+    use rust_decimal::Decimal;
+    use serde::Deserialize;
+
+    use super::super::TransactionType;
+
+    #[derive(Deserialize)]
+    pub struct Account {
+        pub client: u16,
+        pub available: Decimal,
+        pub held: Decimal,
+        pub total: Decimal,
+        pub locked: bool,
+    }
+
+    // a deposit or a withdrawal
+    #[derive(Deserialize)]
+    pub struct Transaction {
+        pub client: u16,
+        pub tx: u32,
+        pub amount: Decimal,
+    }
+
+    #[derive(Deserialize)]
+    pub enum Disputed {
+        Deposit(Transaction),
+        Withdrawal(Transaction),
+    }
+
+    #[derive(Deserialize)]
+    pub enum Record {
+        Deposit(Transaction),
+        Withdrawal(Transaction),
+        Disputed(Disputed),
+        Resolved(Disputed),
+        Chargedback(Disputed),
+    }
+
+    #[derive(Deserialize)]
+    pub struct TransactionData {
+        pub t_type: TransactionType,
+        pub client: u16,
+        pub tx: u32,
+        pub amount: Option<Decimal>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Event {
+        pub seq: u64,
+        pub data: TransactionData,
+    }
+}
+
+// an event as versions 2 and 3 laid it out
+#[derive(Deserialize)]
+pub(crate) struct ShortEvent {
+    seq: u64,
+    t_type: TransactionType,
+    client: ClientID,
+    tx: TxID,
+    amount: Option<Decimal>,
+}
+
+impl From<ShortEvent> for Event {
+    fn from(e: ShortEvent) -> Self {
+        let data = TransactionData {
+            t_type: e.t_type,
+            client: e.client,
+            tx: e.tx,
+            amount: e.amount,
+            operator: None,
+            reason: None,
+        };
+        Event::new(e.seq, data)
+    }
+}
+
+impl From<narrow::Account> for Account {
+    fn from(acc: narrow::Account) -> Self {
+        Account::from(LockedAccount {
+            client: ClientID(acc.client.into()),
+            available: acc.available,
+            held: acc.held,
+            total: acc.total,
+            locked: acc.locked,
+        })
+    }
+}
+
+impl Versioned for Account {
+    fn upgrade(version: u8, body: &[u8]) -> bincode::Result<Self> {
+        match version {
+            0 | 1 => bincode::deserialize::<narrow::Account>(body).map(Account::from),
+            2 => bincode::deserialize::<LockedAccount>(body).map(Account::from),
+            3 => bincode::deserialize(body),
+            v => Err(unknown(v)),
+        }
+    }
+}
+
+fn widen(t: narrow::Transaction) -> (ClientID, TxID, Decimal) {
+    (
+        ClientID(t.client.into()),
+        TxID::numeric(t.tx.into()),
+        t.amount,
+    )
+}
+
+fn widen_disputed(d: narrow::Disputed) -> Disputed {
+    match d {
+        narrow::Disputed::Deposit(t) => {
+            let (client, tx, amount) = widen(t);
+            Disputed::Deposit(Transaction {
+                t: Deposit { client, tx, amount },
+            })
+        }
+        narrow::Disputed::Withdrawal(t) => {
+            let (client, tx, amount) = widen(t);
+            Disputed::Withdrawal(Transaction {
+                t: Withdrawal { client, tx, amount },
+            })
+        }
+    }
+}
+
+impl From<narrow::Record> for Record {
+    fn from(r: narrow::Record) -> Self {
+        match r {
+            narrow::Record::Deposit(t) => {
+                let (client, tx, amount) = widen(t);
+                Record::Deposit(Transaction {
+                    t: Deposit { client, tx, amount },
+                })
+            }
+            narrow::Record::Withdrawal(t) => {
+                let (client, tx, amount) = widen(t);
+                Record::Withdrawal(Transaction {
+                    t: Withdrawal { client, tx, amount },
+                })
+            }
+            narrow::Record::Disputed(d) => Record::Disputed(widen_disputed(d)),
+            narrow::Record::Resolved(d) => Record::Resolved(Resolved {
+                disputed: widen_disputed(d),
+            }),
+            narrow::Record::Chargedback(d) => Record::Chargedback(Chargedback {
+                disputed: widen_disputed(d),
+            }),
+        }
+    }
+}
+
+// versions 3 and 4 changed the accounts and the events alone
+impl Versioned for Record {
+    fn upgrade(version: u8, body: &[u8]) -> bincode::Result<Self> {
+        match version {
+            0 | 1 => bincode::deserialize::<narrow::Record>(body).map(Record::from),
+            2 | 3 => bincode::deserialize(body),
+            v => Err(unknown(v)),
+        }
+    }
+}
+
+impl From<narrow::Event> for Event {
+    fn from(e: narrow::Event) -> Self {
+        let data = TransactionData {
+            t_type: e.data.t_type,
+            client: ClientID(e.data.client.into()),
+            tx: TxID::numeric(e.data.tx.into()),
+            amount: e.data.amount,
+            operator: None,
+            reason: None,
+        };
+        Event::new(e.seq, data)
+    }
+}
+
+impl Versioned for Event {
+    fn upgrade(version: u8, body: &[u8]) -> bincode::Result<Self> {
+        match version {
+            0 | 1 => bincode::deserialize::<narrow::Event>(body).map(Event::from),
+            2 | 3 => bincode::deserialize::<ShortEvent>(body).map(Event::from),
+            v => Err(unknown(v)),
+        }
+    }
+}
+
+fn unknown(version: u8) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(format!(
+        "unknown schema version {}",
+        version
+    )))
+}
+
+pub(crate) fn encode<T: Versioned>(v: &T) -> bincode::Result<Vec<u8>> {
+    let mut bytes = vec![CURRENT];
+    bincode::serialize_into(&mut bytes, v)?;
+    Ok(bytes)
+}
+
+pub(crate) fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, ActionError> {
+    let (version, body) = match bytes.split_first() {
+        Some((v, body)) => (*v, body),
+        None => return Err(ActionError::corrupted("empty record")),
+    };
+
+    let res = if version == CURRENT {
+        bincode::deserialize(body)
+    } else if version < CURRENT {
+        T::upgrade(version, body)
+    } else {
+        return Err(ActionError::corrupted(format!(
+            "the record is written in schema version {}, newer than {}",
+            version, CURRENT
+        )));
+    };
+    res.map_err(ActionError::corrupted)
+}
+
+// upgrades every record of the tree that is not in the current version
+pub(crate) fn migrate<T: Versioned>(tree: &sled::Tree) -> Result<usize, ActionError> {
+    let mut migrated = 0;
+    for res in tree.iter() {
+        let (k, v) = res.map_err(ActionError::storage)?;
+        if version(&v) == Some(CURRENT) {
+            continue;
+        }
+
+        let record: T = decode(&v)?;
+        let bytes = encode(&record).map_err(ActionError::storage)?;
+        tree.insert(k, bytes).map_err(ActionError::storage)?;
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+// the version of the record, without decoding it
+pub(crate) fn version(bytes: &[u8]) -> Option<u8> {
+    bytes.first().copied()
+}
+
+// Makes sure the store can be read with the current schema.
+// A store without a schema version predates the envelope,
+// all of its records get wrapped as version 0 at once.
+// The keys of a store older than version 2 get rewritten at once.
+pub(crate) fn prepare(
+    accounts: &sled::Tree,
+    txs: &sled::Tree,
+    events: &sled::Tree,
+    archive: &sled::Tree,
+    meta: &sled::Tree,
+) -> sled::Result<()> {
+    let v = match meta.get(SCHEMA_KEY)? {
+        Some(v) => version(&v).unwrap_or_default(),
+        None => {
+            wrap(accounts, txs, events, meta)?;
+            1
+        }
+    };
+    if v > STORE {
+        return Err(sled::Error::Unsupported(format!(
+            "the store is written in schema version {}, newer than {}",
+            v, STORE
+        )));
+    }
+    if v < STORE {
+        rekey(accounts, txs, archive, meta, v == 1)?;
+    }
+    Ok(())
+}
+
+// wraps every bare record as version 0
+fn wrap(
+    accounts: &sled::Tree,
+    txs: &sled::Tree,
+    events: &sled::Tree,
+    meta: &sled::Tree,
+) -> sled::Result<()> {
+    let bare = [accounts, txs, events]
+        .iter()
+        .map(|tree| tree.iter().collect::<sled::Result<Vec<_>>>())
+        .collect::<sled::Result<Vec<_>>>()?;
+
+    (accounts, txs, events, meta)
+        .transaction(|(a, t, e, m)| {
+            for (tree, records) in [a, t, e].iter().zip(bare.iter()) {
+                for (k, v) in records {
+                    let mut wrapped = Vec::with_capacity(v.len() + 1);
+                    wrapped.push(0);
+                    wrapped.extend_from_slice(v);
+                    tree.insert(k, wrapped)?;
+                }
+            }
+            m.insert(SCHEMA_KEY, &[1])?;
+            Ok(())
+        })
+        .map_err(abort_free)
+}
+
+// rewrites the narrow keys of versions 1 and 2
+// the way the current version lays them out
+fn rekey(
+    accounts: &sled::Tree,
+    txs: &sled::Tree,
+    archive: &sled::Tree,
+    meta: &sled::Tree,
+    little_endian: bool,
+) -> sled::Result<()> {
+    let mut old = Vec::new();
+    let mut new = Vec::new();
+    for (tree, width) in [(accounts, 2), (txs, 4), (archive, 4)].iter() {
+        let mut keys = Vec::new();
+        let mut records = Vec::new();
+        for res in tree.iter() {
+            let (k, v) = res?;
+            let id = narrow_id(&k, *width, little_endian)?;
+            let key = match width {
+                2 => client_key(&ClientID(id)).to_vec(),
+                _ => tx_key(&TxID::numeric(id)),
+            };
+            keys.push(k);
+            records.push((key, v));
+        }
+        old.push(keys);
+        new.push(records);
+    }
+
+    (accounts, txs, archive, meta)
+        .transaction(|(a, t, r, m)| {
+            for (tree, (keys, records)) in [a, t, r].iter().zip(old.iter().zip(new.iter())) {
+                for k in keys {
+                    tree.remove(k)?;
+                }
+                for (k, v) in records {
+                    tree.insert(&k[..], v)?;
+                }
+            }
+            m.insert(SCHEMA_KEY, &[STORE])?;
+            Ok(())
+        })
+        .map_err(abort_free)
+}
+
+// an id of the given width in bytes, as versions 1 and 2 keyed it
+fn narrow_id(k: &[u8], width: usize, little_endian: bool) -> sled::Result<u64> {
+    if k.len() != width {
+        return Err(sled::Error::Unsupported(format!(
+            "unexpected key {:?}, expected {} bytes",
+            k, width
+        )));
+    }
+    let be = |n: u64, b: &u8| n << 8 | u64::from(*b);
+    Ok(if little_endian {
+        k.iter().rev().fold(0, be)
+    } else {
+        k.iter().fold(0, be)
+    })
+}
+
+fn abort_free(e: TransactionError<()>) -> sled::Error {
+    match e {
+        TransactionError::Abort(()) => unreachable!("nothing aborts"),
+        TransactionError::Storage(e) => e,
+    }
+}
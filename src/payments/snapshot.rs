@@ -0,0 +1,441 @@
+//! A portable copy of everything a [`Container`] holds.
+//!
+//! A snapshot is a header followed by the body:
+//!
+//! | bytes | content                                  |
+//! |-------|------------------------------------------|
+//! | 8     | `PAYMENTS`, marks the file as a snapshot |
+//! | 4     | format version, little endian            |
+//! | 4     | crc32 of the body, little endian         |
+//! | rest  | the body, bincode                        |
+//!
+//! The body holds every account, every transaction, the archived ones
+//! apart, and the event log, in the order it was written.
+//! Version 1 predates the archive, it is read as a store without one.
+//! Versions 1 and 2 hold the narrow ids and the locked flag of their time,
+//! Versions up to 3 hold events without the operator and the reason,
+//! they are brought up to date as they are read.
+
+use std::io::{self, Read, Write};
+use std::{error, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use super::schema::{narrow, ShortEvent};
+use super::{Account, ActionError, Changeset, Container, Event, Record};
+
+const MAGIC: &[u8; 8] = b"PAYMENTS";
+/// The format written by [`export`], [`import`] reads it and the ones before it.
+pub const VERSION: u32 = 4;
+const HEADER_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct Body {
+    accounts: Vec<Account>,
+    records: Vec<Record>,
+    events: Vec<Event>,
+    archived: Vec<Record>,
+}
+
+// the body of version 1
+#[derive(Deserialize)]
+struct BodyV1 {
+    accounts: Vec<narrow::Account>,
+    records: Vec<narrow::Record>,
+    events: Vec<narrow::Event>,
+}
+
+// the body of version 2
+#[derive(Deserialize)]
+struct BodyV2 {
+    accounts: Vec<narrow::Account>,
+    records: Vec<narrow::Record>,
+    events: Vec<narrow::Event>,
+    archived: Vec<narrow::Record>,
+}
+
+// the body of version 3
+#[derive(Deserialize)]
+struct BodyV3 {
+    accounts: Vec<Account>,
+    records: Vec<Record>,
+    events: Vec<ShortEvent>,
+    archived: Vec<Record>,
+}
+
+impl From<BodyV3> for Body {
+    fn from(b: BodyV3) -> Self {
+        Body {
+            accounts: b.accounts,
+            records: b.records,
+            events: b.events.into_iter().map(Event::from).collect(),
+            archived: b.archived,
+        }
+    }
+}
+
+impl From<BodyV2> for Body {
+    fn from(b: BodyV2) -> Self {
+        Body {
+            accounts: b.accounts.into_iter().map(Account::from).collect(),
+            records: b.records.into_iter().map(Record::from).collect(),
+            events: b.events.into_iter().map(Event::from).collect(),
+            archived: b.archived.into_iter().map(Record::from).collect(),
+        }
+    }
+}
+
+/// Writes everything the container holds.
+pub fn export<T: Container>(c: &T, mut w: impl Write) -> Result<(), SnapshotError> {
+    let body = Body {
+        accounts: c.accounts().collect::<Result<_, _>>()?,
+        records: c.transactions().collect::<Result<_, _>>()?,
+        events: c.events(0).collect::<Result<_, _>>()?,
+        archived: c.archived_transactions().collect::<Result<_, _>>()?,
+    };
+    let body = bincode::serialize(&body).map_err(SnapshotError::Corrupted)?;
+
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&checksum(&body).to_le_bytes())?;
+    w.write_all(&body)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Loads a snapshot into an empty container, all at once.
+/// Nothing is loaded unless the whole file checks out.
+/// The events get new sequence numbers from the container,
+/// in the same order as before.
+pub fn import<T: Container>(mut r: impl Read, into: &mut T) -> Result<(), SnapshotError> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf)?;
+
+    if !buf.starts_with(MAGIC) {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    if buf.len() < HEADER_LEN {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let version = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+    if version == 0 || version > VERSION {
+        return Err(SnapshotError::Version(version));
+    }
+
+    let expected = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    let body = &buf[HEADER_LEN..];
+    let found = checksum(body);
+    if expected != found {
+        return Err(SnapshotError::Checksum { expected, found });
+    }
+    let body = match version {
+        1 => bincode::deserialize(body).map(|b: BodyV1| {
+            Body::from(BodyV2 {
+                accounts: b.accounts,
+                records: b.records,
+                events: b.events,
+                archived: Vec::new(),
+            })
+        }),
+        2 => bincode::deserialize::<BodyV2>(body).map(Body::from),
+        3 => bincode::deserialize::<BodyV3>(body).map(Body::from),
+        _ => bincode::deserialize(body),
+    }
+    .map_err(SnapshotError::Corrupted)?;
+
+    let empty = into.accounts().next().is_none()
+        && into.transactions().next().is_none()
+        && into.archived_transactions().next().is_none()
+        && into.events(0).next().is_none();
+    if !empty {
+        return Err(SnapshotError::NotEmpty);
+    }
+
+    let mut changes = Changeset::new();
+    for acc in body.accounts {
+        changes = changes.account(acc);
+    }
+    for r in body.records {
+        changes = changes.record(r);
+    }
+    for e in body.events {
+        changes = changes.log(e.data);
+    }
+    for r in body.archived {
+        changes = changes.archive(r);
+    }
+    into.commit(changes)?;
+    Ok(())
+}
+
+fn checksum(b: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(b);
+    h.finalize()
+}
+
+// prevents users on writing exhaustive code
+// so their code won't break when/if we add new variants
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // the file does not start with the magic bytes
+    NotASnapshot,
+    // the file ends before the header does
+    Truncated,
+    // written in a format this version cannot read
+    Version(u32),
+    // the body is not what was written
+    Checksum { expected: u32, found: u32 },
+    // the body cannot be encoded or decoded
+    Corrupted(bincode::Error),
+    // a snapshot is only ever loaded into an empty container
+    NotEmpty,
+    Storage(ActionError),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<ActionError> for SnapshotError {
+    fn from(e: ActionError) -> Self {
+        SnapshotError::Storage(e)
+    }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "cannot access the snapshot: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::Truncated => write!(f, "the snapshot is truncated"),
+            SnapshotError::Version(v) => write!(
+                f,
+                "snapshot format version {} is not supported, expected {} or older",
+                v, VERSION
+            ),
+            SnapshotError::Checksum { expected, found } => write!(
+                f,
+                "the snapshot is corrupted, checksum {:08x} does not match {:08x}",
+                found, expected
+            ),
+            SnapshotError::Corrupted(e) => write!(f, "the snapshot is corrupted: {}", e),
+            SnapshotError::NotEmpty => {
+                write!(f, "a snapshot can only be loaded into an empty store")
+            }
+            SnapshotError::Storage(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            SnapshotError::Corrupted(e) => Some(e),
+            SnapshotError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::payments::{
+        round_serialize, Accounts, Archive, Cause, ClientID, MemoryContainer, Status,
+        TransactionData, TransactionType, TxID, DB,
+    };
+
+    fn filled() -> Accounts<MemoryContainer> {
+        let mut actts = Accounts::new(MemoryContainer::new());
+        for (t_type, client, tx, amount) in [
+            (TransactionType::Deposit, 1, 1, Some(10)),
+            (TransactionType::Deposit, 2, 2, Some(5)),
+            (TransactionType::Withdrawal, 1, 3, Some(4)),
+            (TransactionType::Dispute, 2, 2, None),
+        ]
+        .iter()
+        {
+            actts
+                .process(TransactionData {
+                    t_type: *t_type,
+                    client: ClientID(*client),
+                    tx: TxID::numeric(*tx),
+                    amount: amount.map(Decimal::from),
+                    operator: None,
+                    reason: None,
+                })
+                .unwrap();
+        }
+        actts
+    }
+
+    fn sorted<T, K: Ord>(mut v: Vec<T>, key: impl Fn(&T) -> K) -> Vec<T> {
+        v.sort_by_key(key);
+        v
+    }
+
+    #[test]
+    fn restores_everything() {
+        let mut from = filled();
+        // everything but the open dispute
+        assert_eq!(from.archive(Archive::After(0)), Ok(2));
+        let mut file = Vec::new();
+        from.export(&mut file).unwrap();
+
+        let mut to = Accounts::new(
+            DB::ephemeral(sled::Config::new().temporary(true).open().unwrap()).unwrap(),
+        );
+        to.import(&file[..]).unwrap();
+
+        let accounts =
+            |c: &dyn Container| sorted(c.accounts().map(Result::unwrap).collect(), Account::client);
+        let records = |c: &dyn Container| {
+            sorted(
+                c.transactions().map(Result::unwrap).collect(),
+                |r: &Record| r.tx().clone(),
+            )
+        };
+        assert_eq!(accounts(&to.db), accounts(&from.db));
+        assert_eq!(records(&to.db), records(&from.db));
+        let archived = |c: &dyn Container| {
+            sorted(
+                c.archived_transactions().map(Result::unwrap).collect(),
+                |r: &Record| r.tx().clone(),
+            )
+        };
+        assert_eq!(archived(&to.db).len(), 2);
+        assert_eq!(archived(&to.db), archived(&from.db));
+        assert_eq!(
+            to.events().map(Result::unwrap).collect::<Vec<_>>(),
+            from.events().map(Result::unwrap).collect::<Vec<_>>()
+        );
+
+        assert!(matches!(to.import(&file[..]), Err(SnapshotError::NotEmpty)));
+    }
+
+    #[test]
+    fn refuses_damaged_files() {
+        let mut file = Vec::new();
+        filled().export(&mut file).unwrap();
+        let load = |f: &[u8]| Accounts::new(MemoryContainer::new()).import(f);
+
+        assert!(matches!(
+            load(b"type, client, tx, amount"),
+            Err(SnapshotError::NotASnapshot)
+        ));
+        assert!(matches!(load(&file[..10]), Err(SnapshotError::Truncated)));
+
+        let mut other = file.clone();
+        other[8] = 5;
+        assert!(matches!(load(&other), Err(SnapshotError::Version(5))));
+
+        let mut flipped = file.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert!(matches!(
+            load(&flipped),
+            Err(SnapshotError::Checksum { .. })
+        ));
+
+        let cut = &file[..file.len() - 1];
+        assert!(matches!(load(cut), Err(SnapshotError::Checksum { .. })));
+    }
+
+    #[test]
+    fn reads_the_narrow_ids_of_version_2() {
+        // laid out the way version 2 wrote them
+        #[derive(Serialize)]
+        struct OldAccount {
+            client: u16,
+            #[serde(serialize_with = "round_serialize")]
+            available: Decimal,
+            #[serde(serialize_with = "round_serialize")]
+            held: Decimal,
+            #[serde(serialize_with = "round_serialize")]
+            total: Decimal,
+            locked: bool,
+        }
+        #[derive(Serialize)]
+        struct OldDeposit {
+            client: u16,
+            tx: u32,
+            amount: Decimal,
+        }
+        #[derive(Serialize)]
+        enum OldRecord {
+            Deposit(OldDeposit),
+        }
+        #[derive(Serialize)]
+        struct OldData {
+            t_type: TransactionType,
+            client: u16,
+            tx: u32,
+            amount: Option<Decimal>,
+        }
+        #[derive(Serialize)]
+        struct OldEvent {
+            seq: u64,
+            data: OldData,
+        }
+        #[derive(Serialize)]
+        struct OldBody {
+            accounts: Vec<OldAccount>,
+            records: Vec<OldRecord>,
+            events: Vec<OldEvent>,
+            archived: Vec<OldRecord>,
+        }
+
+        let body = bincode::serialize(&OldBody {
+            accounts: vec![OldAccount {
+                client: 300,
+                available: Decimal::from(5),
+                held: Decimal::from(0),
+                total: Decimal::from(5),
+                locked: true,
+            }],
+            records: vec![OldRecord::Deposit(OldDeposit {
+                client: 300,
+                tx: 70000,
+                amount: Decimal::from(5),
+            })],
+            events: vec![OldEvent {
+                seq: 1,
+                data: OldData {
+                    t_type: TransactionType::Deposit,
+                    client: 300,
+                    tx: 70000,
+                    amount: Some(Decimal::from(5)),
+                },
+            }],
+            archived: Vec::new(),
+        })
+        .unwrap();
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&2u32.to_le_bytes());
+        file.extend_from_slice(&checksum(&body).to_le_bytes());
+        file.extend_from_slice(&body);
+
+        let mut to = Accounts::new(MemoryContainer::new());
+        to.import(&file[..]).unwrap();
+        let acc = to.db.get_account(&ClientID(300)).unwrap();
+        assert_eq!(acc.total(), Decimal::from(5));
+        assert_eq!(
+            acc.status(),
+            &Status::Frozen(Cause::new("chargeback", None))
+        );
+        assert!(matches!(
+            to.transaction(&TxID::numeric(70000)),
+            Ok(Some(Record::Deposit(_)))
+        ));
+        assert_eq!(to.events().count(), 1);
+    }
+}
@@ -0,0 +1,988 @@
+use std::convert::TryFrom;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use rust_decimal::Decimal;
+
+use super::{
+    Account, ActionError, Cause, Changeset, Chargedback, ClientID, Container, Deposit, Disputed,
+    Event, Id, Ordered, Record, Resolved, State, Status, Transaction, TransactionData,
+    TransactionType, TxID, Withdrawal,
+};
+
+// Keeps everything in an SQLite database
+// laid out so it can be queried with any SQL tool
+//
+// the amounts are stored as text, they are exact decimals
+// SQLite would round them as floating point numbers
+//
+// the transaction ids are numbers or text, their columns take both
+// SQLite integers are signed, so the ids above i64::MAX, clients and
+// transactions alike, are stored as eight big endian bytes instead
+// SQLite sorts the blobs after the integers, so they keep their order
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    client    NOT NULL PRIMARY KEY,
+    available TEXT NOT NULL,
+    held      TEXT NOT NULL,
+    total     TEXT NOT NULL,
+    -- anything but active
+    locked    INTEGER NOT NULL,
+    -- the lifecycle state, why the account got there and the transaction that did it
+    state     TEXT NOT NULL DEFAULT 'active'
+              CHECK (state IN ('active', 'frozen', 'suspended', 'closed')),
+    reason    TEXT,
+    cause_tx
+);
+
+-- deposits and withdrawals, as they came in
+-- and the administrative operations, so their ids stay taken
+CREATE TABLE IF NOT EXISTS transactions (
+    tx     NOT NULL PRIMARY KEY,
+    client INTEGER NOT NULL,
+    kind   TEXT NOT NULL
+           CHECK (kind IN ('deposit', 'withdrawal', 'unlock', 'freeze', 'suspend', 'close')),
+    amount TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transactions_client ON transactions (client);
+
+-- a transaction without a row here was never disputed
+CREATE TABLE IF NOT EXISTS disputes (
+    tx    NOT NULL PRIMARY KEY REFERENCES transactions (tx),
+    state TEXT NOT NULL CHECK (state IN ('open', 'resolved', 'chargedback'))
+);
+
+-- the settled transactions, moved out of the two tables above
+-- with the state of their dispute, if there was one
+CREATE TABLE IF NOT EXISTS archive (
+    tx     NOT NULL PRIMARY KEY,
+    client INTEGER NOT NULL,
+    kind   TEXT NOT NULL
+           CHECK (kind IN ('deposit', 'withdrawal', 'unlock', 'freeze', 'suspend', 'close')),
+    amount TEXT NOT NULL,
+    state  TEXT CHECK (state IN ('resolved', 'chargedback'))
+);
+CREATE INDEX IF NOT EXISTS archive_client ON archive (client);
+
+CREATE TABLE IF NOT EXISTS events (
+    seq      INTEGER PRIMARY KEY,
+    type     TEXT NOT NULL,
+    client   INTEGER NOT NULL,
+    tx       NOT NULL,
+    amount   TEXT,
+    -- who ordered an administrative operation and why
+    operator TEXT,
+    reason   TEXT
+);
+
+-- the last event Accounts::archive went through, a single row
+CREATE TABLE IF NOT EXISTS archive_mark (
+    id  INTEGER PRIMARY KEY CHECK (id = 0),
+    seq INTEGER NOT NULL
+);
+";
+
+// version 3 lets the transaction ids be text as well as numbers
+// and the ids above i64::MAX be stored as blobs, the accounts were
+// keyed by their rowid, which only takes integers
+// the tables keyed by them are set aside, made anew and filled again
+const WIDEN_IDS: &str = "
+ALTER TABLE accounts RENAME TO old_accounts;
+DROP INDEX IF EXISTS transactions_client;
+DROP INDEX IF EXISTS archive_client;
+ALTER TABLE transactions RENAME TO old_transactions;
+ALTER TABLE disputes RENAME TO old_disputes;
+ALTER TABLE events RENAME TO old_events;
+CREATE TABLE IF NOT EXISTS archive (tx, client, kind, amount, state);
+ALTER TABLE archive RENAME TO old_archive;
+";
+
+const REFILL: &str = "
+INSERT INTO accounts (client, available, held, total, locked)
+    SELECT client, available, held, total, locked FROM old_accounts;
+INSERT INTO transactions SELECT tx, client, kind, amount FROM old_transactions;
+INSERT INTO disputes SELECT tx, state FROM old_disputes;
+INSERT INTO archive SELECT tx, client, kind, amount, state FROM old_archive;
+INSERT INTO events (seq, type, client, tx, amount)
+    SELECT seq, type, client, tx, amount FROM old_events;
+DROP TABLE old_accounts;
+DROP TABLE old_disputes;
+DROP TABLE old_transactions;
+DROP TABLE old_archive;
+DROP TABLE old_events;
+";
+
+// version 4 keeps the lifecycle state of the accounts
+const ADD_STATE: &str = "
+ALTER TABLE accounts ADD COLUMN state TEXT NOT NULL DEFAULT 'active'
+    CHECK (state IN ('active', 'frozen', 'suspended', 'closed'));
+ALTER TABLE accounts ADD COLUMN reason TEXT;
+ALTER TABLE accounts ADD COLUMN cause_tx;
+";
+
+// only a chargeback ever locked one before
+const FREEZE_LOCKED: &str = "
+UPDATE accounts SET state = 'frozen', reason = 'chargeback' WHERE locked;
+";
+
+// version 5 logs the operator and the reason of administrative operations
+// the events of versions 1 and 2 are made anew with them, see WIDEN_IDS
+const ADD_OPERATOR: &str = "
+ALTER TABLE events ADD COLUMN operator TEXT;
+ALTER TABLE events ADD COLUMN reason TEXT;
+";
+
+// version 6 stores the administrative operations with the transactions
+// the tables that check the kind are set aside, made anew and filled again
+const WIDEN_KINDS: &str = "
+DROP INDEX IF EXISTS transactions_client;
+DROP INDEX IF EXISTS archive_client;
+ALTER TABLE transactions RENAME TO old_transactions;
+ALTER TABLE disputes RENAME TO old_disputes;
+ALTER TABLE archive RENAME TO old_archive;
+";
+
+const REFILL_KINDS: &str = "
+INSERT INTO transactions SELECT tx, client, kind, amount FROM old_transactions;
+INSERT INTO disputes SELECT tx, state FROM old_disputes;
+INSERT INTO archive SELECT tx, client, kind, amount, state FROM old_archive;
+DROP TABLE old_disputes;
+DROP TABLE old_transactions;
+DROP TABLE old_archive;
+";
+
+// bumped whenever the tables change
+const SCHEMA_VERSION: i64 = 6;
+
+// rows are read this many at a time
+// so enumerating a big table does not load all of it
+const PAGE: i64 = 1000;
+
+pub struct SqliteContainer {
+    conn: Connection,
+}
+
+impl SqliteContainer {
+    // the database outlives the process
+    // it is locked while it is open, another run fails to open it
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "locking_mode", "EXCLUSIVE")?;
+        conn.busy_timeout(Duration::from_secs(0))?;
+        Self::init(conn)
+    }
+
+    // everything is gone once the container is dropped
+    pub fn in_memory() -> rusqlite::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> rusqlite::Result<Self> {
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CANTOPEN),
+                Some(format!(
+                    "the database is written in schema version {}, newer than {}",
+                    version, SCHEMA_VERSION
+                )),
+            ));
+        }
+
+        let mut conn = conn;
+        let t = conn.transaction()?;
+        if version > 0 && version < 3 {
+            t.execute_batch(WIDEN_IDS)?;
+            t.execute_batch(SCHEMA)?;
+            t.execute_batch(REFILL)?;
+        }
+        // the accounts of versions 1 and 2 are made anew already, see WIDEN_IDS
+        if version > 2 && version < 4 {
+            t.execute_batch(ADD_STATE)?;
+        }
+        if version > 0 && version < 4 {
+            t.execute_batch(FREEZE_LOCKED)?;
+        }
+        if version > 2 && version < 5 {
+            t.execute_batch(ADD_OPERATOR)?;
+        }
+        // the tables of versions 1 and 2 are made anew already, see WIDEN_IDS
+        if version > 2 && version < 6 {
+            t.execute_batch(WIDEN_KINDS)?;
+            t.execute_batch(SCHEMA)?;
+            t.execute_batch(REFILL_KINDS)?;
+        }
+        t.execute_batch(SCHEMA)?;
+        t.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        t.commit()?;
+        Ok(Self { conn })
+    }
+
+    // reads a table page by page, in the order of its key
+    fn paged<'a, K: Key + 'a, T: 'a>(
+        &'a self,
+        sql: &'static str,
+        from: K,
+        read: fn(&Row) -> Result<(K, T), ActionError>,
+    ) -> Box<dyn Iterator<Item = Result<T, ActionError>> + 'a> {
+        let mut next = Some(from);
+        let mut page = Vec::new().into_iter();
+
+        Box::new(std::iter::from_fn(move || loop {
+            if let Some(item) = page.next() {
+                return Some(item);
+            }
+
+            let from = next.take()?;
+            let rows = self.query_page(sql, &from, read);
+            let rows = match rows {
+                Ok(rows) => rows,
+                Err(e) => return Some(Err(e)),
+            };
+
+            // a full page means there might be more
+            if rows.len() as i64 == PAGE {
+                next = rows.last().and_then(|(key, _)| key.next());
+            }
+            page = rows
+                .into_iter()
+                .map(|(_, item)| Ok(item))
+                .collect::<Vec<_>>()
+                .into_iter();
+            if page.len() == 0 {
+                return None;
+            }
+        }))
+    }
+
+    fn query_page<K: Key, T>(
+        &self,
+        sql: &str,
+        from: &K,
+        read: fn(&Row) -> Result<(K, T), ActionError>,
+    ) -> Result<Vec<(K, T)>, ActionError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(sql)
+            .map_err(ActionError::storage)?;
+        let mut rows = stmt
+            .query(params![from, PAGE])
+            .map_err(ActionError::storage)?;
+
+        let mut page = Vec::new();
+        while let Some(row) = rows.next().map_err(ActionError::storage)? {
+            page.push(read(row)?);
+        }
+        Ok(page)
+    }
+}
+
+// what the pages of a table are read by
+trait Key: ToSql + Sized {
+    // the key right after this one
+    fn next(&self) -> Option<Self>;
+}
+
+impl Key for i64 {
+    fn next(&self) -> Option<Self> {
+        self.checked_add(1)
+    }
+}
+
+impl Key for ClientID {
+    fn next(&self) -> Option<Self> {
+        self.0.checked_add(1).map(ClientID)
+    }
+}
+
+fn decimal(s: &str) -> Result<Decimal, ActionError> {
+    Decimal::from_str(s).map_err(ActionError::corrupted)
+}
+
+fn get<T: rusqlite::types::FromSql>(row: &Row, i: usize) -> Result<T, ActionError> {
+    row.get(i).map_err(ActionError::corrupted)
+}
+
+// an integer if it fits, eight big endian bytes if it does not
+fn number(id: u64) -> ToSqlOutput<'static> {
+    match i64::try_from(id) {
+        Ok(id) => ToSqlOutput::from(id),
+        Err(_) => ToSqlOutput::from(id.to_be_bytes().to_vec()),
+    }
+}
+
+fn read_number(v: ValueRef<'_>) -> FromSqlResult<u64> {
+    match v {
+        ValueRef::Integer(id) => u64::try_from(id).map_err(|_| FromSqlError::OutOfRange(id)),
+        ValueRef::Blob(b) => <[u8; 8]>::try_from(b).map(u64::from_be_bytes).map_err(|_| {
+            FromSqlError::InvalidBlobSize {
+                expected_size: 8,
+                blob_size: b.len(),
+            }
+        }),
+        _ => Err(FromSqlError::InvalidType),
+    }
+}
+
+impl ToSql for ClientID {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(number(self.0))
+    }
+}
+
+impl FromSql for ClientID {
+    fn column_result(v: ValueRef<'_>) -> FromSqlResult<Self> {
+        read_number(v).map(ClientID)
+    }
+}
+
+impl ToSql for TxID {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match &self.0 {
+            Id::Numeric(id) => Ok(number(*id)),
+            Id::Opaque(id) => Ok(ToSqlOutput::from(id.as_str())),
+        }
+    }
+}
+
+impl FromSql for TxID {
+    fn column_result(v: ValueRef<'_>) -> FromSqlResult<Self> {
+        match v {
+            ValueRef::Integer(_) | ValueRef::Blob(_) => read_number(v).map(TxID::numeric),
+            ValueRef::Text(_) => v
+                .as_str()?
+                .parse()
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+// the rows are put back together with the public constructors
+// the same way a storage outside of the crate does
+fn read_account(row: &Row) -> Result<(ClientID, Account), ActionError> {
+    let client = get(row, 0)?;
+    let acc = Account::from_parts(
+        client,
+        decimal(&get::<String>(row, 1)?)?,
+        decimal(&get::<String>(row, 2)?)?,
+        decimal(&get::<String>(row, 3)?)?,
+        read_status(row)?,
+    );
+    Ok((client, acc))
+}
+
+// state, reason, cause_tx
+fn read_status(row: &Row) -> Result<Status, ActionError> {
+    let state: String = get(row, 4)?;
+    let cause = || -> Result<Cause, ActionError> {
+        let reason: Option<String> = get(row, 5)?;
+        Ok(Cause::new(reason.unwrap_or_default(), get(row, 6)?))
+    };
+    Ok(match state.as_str() {
+        "active" => Status::Active,
+        "frozen" => Status::Frozen(cause()?),
+        "suspended" => Status::Suspended(cause()?),
+        "closed" => Status::Closed(cause()?),
+        other => {
+            return Err(ActionError::corrupted(format!(
+                "unknown account state {:?}",
+                other
+            )))
+        }
+    })
+}
+
+// rowid, tx, client, kind, amount, dispute state
+// the ids can be text, the pages go by the rowid
+fn read_record(row: &Row) -> Result<(i64, Record), ActionError> {
+    let rowid: i64 = get(row, 0)?;
+    let tx = get(row, 1)?;
+    let client = get(row, 2)?;
+    let amount = decimal(&get::<String>(row, 4)?)?;
+
+    // the transaction as it came in, wrapped
+    // the same way whatever its dispute state is
+    let kind: String = get(row, 3)?;
+    let disputed = match kind.as_str() {
+        "deposit" => Disputed::Deposit(Transaction::<Deposit>::from_parts(client, tx, amount)),
+        "withdrawal" => {
+            Disputed::Withdrawal(Transaction::<Withdrawal>::from_parts(client, tx, amount))
+        }
+        // an administrative operation, it is never disputed
+        other => match parse_type(other) {
+            Some(t_type) if t_type.is_admin() => {
+                // only a close pays out
+                let amount = Some(amount).filter(|_| t_type == TransactionType::Close);
+                let ordered = Ordered::from_parts(t_type, client, tx, amount);
+                return Ok((rowid, Record::Ordered(ordered)));
+            }
+            _ => {
+                return Err(ActionError::corrupted(format!(
+                    "unknown transaction kind {:?}",
+                    other
+                )))
+            }
+        },
+    };
+
+    let state: Option<String> = get(row, 5)?;
+    let record = match (state.as_deref(), disputed) {
+        (None, Disputed::Deposit(d)) => Record::Deposit(d),
+        (None, Disputed::Withdrawal(w)) => Record::Withdrawal(w),
+        (Some("open"), d) => Record::Disputed(d),
+        (Some("resolved"), disputed) => Record::Resolved(Resolved::new(disputed)),
+        (Some("chargedback"), disputed) => Record::Chargedback(Chargedback::new(disputed)),
+        (Some(other), _) => {
+            return Err(ActionError::corrupted(format!(
+                "unknown dispute state {:?}",
+                other
+            )))
+        }
+    };
+    Ok((rowid, record))
+}
+
+fn read_event(row: &Row) -> Result<(i64, Event), ActionError> {
+    let seq: i64 = get(row, 0)?;
+    let t_type: String = get(row, 1)?;
+    let t_type = parse_type(&t_type)
+        .ok_or_else(|| ActionError::corrupted(format!("unknown event type {:?}", t_type)))?;
+    let amount = match get::<Option<String>>(row, 4)? {
+        Some(a) => Some(decimal(&a)?),
+        None => None,
+    };
+
+    let data = TransactionData::from_parts(
+        t_type,
+        get(row, 2)?,
+        get(row, 3)?,
+        amount,
+        get(row, 5)?,
+        get(row, 6)?,
+    );
+    Ok((seq, Event::new(seq as u64, data)))
+}
+
+fn state_name(s: State) -> &'static str {
+    match s {
+        State::Active => "active",
+        State::Frozen => "frozen",
+        State::Suspended => "suspended",
+        State::Closed => "closed",
+    }
+}
+
+fn parse_type(name: &str) -> Option<TransactionType> {
+    Some(match name {
+        "deposit" => TransactionType::Deposit,
+        "withdrawal" => TransactionType::Withdrawal,
+        "dispute" => TransactionType::Dispute,
+        "resolve" => TransactionType::Resolve,
+        "chargeback" => TransactionType::Chargeback,
+        "unlock" => TransactionType::Unlock,
+        "freeze" => TransactionType::Freeze,
+        "suspend" => TransactionType::Suspend,
+        "close" => TransactionType::Close,
+        _ => return None,
+    })
+}
+
+fn type_name(t: TransactionType) -> &'static str {
+    match t {
+        TransactionType::Deposit => "deposit",
+        TransactionType::Withdrawal => "withdrawal",
+        TransactionType::Dispute => "dispute",
+        TransactionType::Resolve => "resolve",
+        TransactionType::Chargeback => "chargeback",
+        TransactionType::Unlock => "unlock",
+        TransactionType::Freeze => "freeze",
+        TransactionType::Suspend => "suspend",
+        TransactionType::Close => "close",
+    }
+}
+
+impl Container for SqliteContainer {
+    fn get_account(&self, id: &ClientID) -> Result<Account, ActionError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT client, available, held, total, state, reason, cause_tx
+                 FROM accounts WHERE client = ?",
+            )
+            .map_err(ActionError::storage)?;
+        let mut rows = stmt.query([id]).map_err(ActionError::storage)?;
+
+        match rows.next().map_err(ActionError::storage)? {
+            Some(row) => read_account(row).map(|(_, acc)| acc),
+            None => Err(ActionError::InvalidClientID),
+        }
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        self.paged(
+            "SELECT client, available, held, total, state, reason, cause_tx
+             FROM accounts WHERE client >= ? ORDER BY client LIMIT ?",
+            ClientID(0),
+            read_account,
+        )
+    }
+
+    fn accounts_range(
+        &self,
+        clients: (Bound<ClientID>, Bound<ClientID>),
+    ) -> Box<dyn Iterator<Item = Result<Account, ActionError>> + '_> {
+        let from = match clients.0 {
+            Bound::Included(id) => Some(id),
+            Bound::Excluded(id) => id.next(),
+            Bound::Unbounded => Some(ClientID(0)),
+        };
+        // nothing comes after the last client
+        let from = match from {
+            Some(from) => from,
+            None => return Box::new(std::iter::empty()),
+        };
+        // the pages stop being read past the end
+        let accounts = self.paged(
+            "SELECT client, available, held, total, state, reason, cause_tx
+             FROM accounts WHERE client >= ? ORDER BY client LIMIT ?",
+            from,
+            read_account,
+        );
+        Box::new(accounts.take_while(move |acc| match acc {
+            Ok(acc) => clients.contains(&acc.client),
+            Err(_) => true,
+        }))
+    }
+
+    fn transaction(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT t.rowid, t.tx, t.client, t.kind, t.amount, d.state
+                 FROM transactions t LEFT JOIN disputes d ON d.tx = t.tx
+                 WHERE t.tx = ?",
+            )
+            .map_err(ActionError::storage)?;
+        let mut rows = stmt.query([tx]).map_err(ActionError::storage)?;
+
+        match rows.next().map_err(ActionError::storage)? {
+            Some(row) => read_record(row).map(|(_, r)| Some(r)),
+            None => Ok(None),
+        }
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        self.paged(
+            "SELECT t.rowid, t.tx, t.client, t.kind, t.amount, d.state
+             FROM transactions t LEFT JOIN disputes d ON d.tx = t.tx
+             WHERE t.rowid >= ? ORDER BY t.rowid LIMIT ?",
+            0,
+            read_record,
+        )
+    }
+
+    fn archived(&self, tx: &TxID) -> Result<Option<Record>, ActionError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT rowid, tx, client, kind, amount, state FROM archive WHERE tx = ?",
+            )
+            .map_err(ActionError::storage)?;
+        let mut rows = stmt.query([tx]).map_err(ActionError::storage)?;
+
+        match rows.next().map_err(ActionError::storage)? {
+            Some(row) => read_record(row).map(|(_, r)| Some(r)),
+            None => Ok(None),
+        }
+    }
+
+    fn archived_transactions(&self) -> Box<dyn Iterator<Item = Result<Record, ActionError>> + '_> {
+        self.paged(
+            "SELECT rowid, tx, client, kind, amount, state FROM archive
+             WHERE rowid >= ? ORDER BY rowid LIMIT ?",
+            0,
+            read_record,
+        )
+    }
+
+    fn commit(&mut self, changes: Changeset) -> Result<(), ActionError> {
+        // nothing is stored unless the transaction is committed
+        let t = self.conn.transaction().map_err(ActionError::storage)?;
+
+        for acc in changes.accounts() {
+            t.prepare_cached(
+                "INSERT OR REPLACE INTO accounts
+                 (client, available, held, total, locked, state, reason, cause_tx)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .and_then(|mut stmt| {
+                let cause = acc.status.cause();
+                stmt.execute(params![
+                    acc.client,
+                    acc.available.to_string(),
+                    acc.held.to_string(),
+                    acc.total.to_string(),
+                    acc.locked(),
+                    state_name(acc.state()),
+                    cause.map(Cause::reason),
+                    cause.and_then(Cause::tx),
+                ])
+            })
+            .map_err(ActionError::storage)?;
+        }
+
+        for r in changes.records() {
+            let (kind, state) = kind_and_state(r);
+            t.prepare_cached(
+                "INSERT OR REPLACE INTO transactions (tx, client, kind, amount)
+                 VALUES (?, ?, ?, ?)",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![r.tx(), r.client(), kind, r.amount().to_string()])
+            })
+            .map_err(ActionError::storage)?;
+
+            let res = match state {
+                Some(state) => t
+                    .prepare_cached("INSERT OR REPLACE INTO disputes (tx, state) VALUES (?, ?)")
+                    .and_then(|mut stmt| stmt.execute(params![r.tx(), state])),
+                None => t
+                    .prepare_cached("DELETE FROM disputes WHERE tx = ?")
+                    .and_then(|mut stmt| stmt.execute(params![r.tx()])),
+            };
+            res.map_err(ActionError::storage)?;
+        }
+
+        let last: Option<i64> = t
+            .query_row("SELECT MAX(seq) FROM events", [], |r| r.get(0))
+            .optional()
+            .map_err(ActionError::storage)?
+            .flatten();
+        for (data, seq) in changes.events().iter().zip(last.unwrap_or(0) + 1..) {
+            t.prepare_cached(
+                "INSERT INTO events (seq, type, client, tx, amount, operator, reason)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    seq,
+                    type_name(data.t_type),
+                    data.client,
+                    data.tx,
+                    data.amount.map(|a| a.to_string()),
+                    data.operator,
+                    data.reason,
+                ])
+            })
+            .map_err(ActionError::storage)?;
+        }
+
+        for r in changes.archived() {
+            let (kind, state) = kind_and_state(r);
+            t.prepare_cached(
+                "INSERT OR REPLACE INTO archive (tx, client, kind, amount, state)
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    r.tx(),
+                    r.client(),
+                    kind,
+                    r.amount().to_string(),
+                    state
+                ])
+            })
+            .and_then(|_| t.prepare_cached("DELETE FROM disputes WHERE tx = ?"))
+            .and_then(|mut stmt| stmt.execute(params![r.tx()]))
+            .and_then(|_| t.prepare_cached("DELETE FROM transactions WHERE tx = ?"))
+            .and_then(|mut stmt| stmt.execute(params![r.tx()]))
+            .map_err(ActionError::storage)?;
+        }
+
+        if let Some(mark) = changes.archive_mark() {
+            t.prepare_cached("INSERT OR REPLACE INTO archive_mark (id, seq) VALUES (0, ?)")
+                .and_then(|mut stmt| stmt.execute(params![mark as i64]))
+                .map_err(ActionError::storage)?;
+        }
+
+        t.commit().map_err(ActionError::storage)
+    }
+
+    fn events(&self, from: u64) -> Box<dyn Iterator<Item = Result<Event, ActionError>> + '_> {
+        self.paged(
+            "SELECT seq, type, client, tx, amount, operator, reason FROM events
+             WHERE seq >= ? ORDER BY seq LIMIT ?",
+            from as i64,
+            read_event,
+        )
+    }
+
+    fn last_seq(&self) -> Result<u64, ActionError> {
+        let last: Option<i64> = self
+            .conn
+            .query_row("SELECT MAX(seq) FROM events", [], |r| r.get(0))
+            .map_err(ActionError::storage)?;
+        Ok(last.unwrap_or(0) as u64)
+    }
+
+    // counted by SQLite, nothing is read out
+    fn transaction_count(&self) -> Result<u64, ActionError> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM transactions", [], |r| r.get(0))
+            .map_err(ActionError::storage)?;
+        Ok(count as u64)
+    }
+
+    fn archive_mark(&self) -> Result<u64, ActionError> {
+        let mark: Option<i64> = self
+            .conn
+            .query_row("SELECT seq FROM archive_mark", [], |r| r.get(0))
+            .optional()
+            .map_err(ActionError::storage)?;
+        Ok(mark.unwrap_or(0) as u64)
+    }
+}
+
+// the kind of the transaction and the state of its dispute
+fn kind_and_state(r: &Record) -> (&'static str, Option<&'static str>) {
+    match r {
+        Record::Deposit(_) => ("deposit", None),
+        Record::Withdrawal(_) => ("withdrawal", None),
+        Record::Disputed(d) => (disputed_kind(d), Some("open")),
+        Record::Resolved(r) => (disputed_kind(&r.disputed), Some("resolved")),
+        Record::Chargedback(c) => (disputed_kind(&c.disputed), Some("chargedback")),
+        Record::Ordered(o) => (type_name(o.t_type), None),
+    }
+}
+
+fn disputed_kind(d: &Disputed) -> &'static str {
+    match d {
+        Disputed::Deposit(_) => "deposit",
+        Disputed::Withdrawal(_) => "withdrawal",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payments::conformance::data;
+    use crate::payments::Accounts;
+
+    #[test]
+    fn plain_sql_and_paging() {
+        let path = std::env::temp_dir().join(format!("payments-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let mut accts = Accounts::new(SqliteContainer::open(&path).unwrap());
+            // more than a page of everything
+            for client in 1..=2500 {
+                accts
+                    .process(data(TransactionType::Deposit, client, client, Some(2)))
+                    .unwrap();
+            }
+            accts
+                .process(data(TransactionType::Dispute, 7, 7, None))
+                .unwrap();
+        }
+
+        let accts = Accounts::new(SqliteContainer::open(&path).unwrap());
+        assert_eq!(accts.iter().count(), 2500);
+        assert_eq!(accts.db.transactions().count(), 2500);
+        assert_eq!(accts.events().count(), 2501);
+        assert_eq!(accts.db.events(2500).count(), 2);
+
+        // one run at a time
+        assert!(SqliteContainer::open(&path).is_err());
+        drop(accts);
+
+        let conn = Connection::open(&path).unwrap();
+        let (kind, state): (String, String) = conn
+            .query_row(
+                "SELECT t.kind, d.state FROM transactions t JOIN disputes d ON d.tx = t.tx",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((kind.as_str(), state.as_str()), ("deposit", "open"));
+
+        let held: String = conn
+            .query_row("SELECT held FROM accounts WHERE client = 7", [], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_eq!(held, "2");
+
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ids_beyond_i64_are_stored() {
+        let mut accts = Accounts::new(SqliteContainer::in_memory().unwrap());
+        let big = i64::MAX as u64 + 1;
+        for (client, tx) in [
+            (u64::MAX, 1),
+            (big, big),
+            (1, u64::MAX),
+            (i64::MAX as u64, 2),
+        ] {
+            accts
+                .process(data(TransactionType::Deposit, client, tx, Some(2)))
+                .unwrap();
+        }
+
+        let clients = accts
+            .iter()
+            .map(|acc| acc.unwrap().client.get())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            clients,
+            vec![1, i64::MAX as u64, big, u64::MAX],
+            "the clients keep their order"
+        );
+        assert_eq!(accts.range(ClientID::new(big)..).count(), 2);
+        assert_eq!(accts.range(..ClientID::new(big)).count(), 2);
+
+        for tx in [big, u64::MAX] {
+            assert!(accts.transaction(&TxID::numeric(tx)).unwrap().is_some());
+        }
+        let events = accts
+            .events()
+            .map(|e| e.unwrap().data().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events[0],
+            data(TransactionType::Deposit, u64::MAX, 1, Some(2))
+        );
+    }
+
+    #[test]
+    fn version_2_is_brought_up_to_date() {
+        // laid out the way version 2 was
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE accounts (client INTEGER PRIMARY KEY, available TEXT NOT NULL,
+                held TEXT NOT NULL, total TEXT NOT NULL, locked INTEGER NOT NULL);
+            CREATE TABLE transactions (tx INTEGER PRIMARY KEY, client INTEGER NOT NULL,
+                kind TEXT NOT NULL, amount TEXT NOT NULL);
+            CREATE INDEX transactions_client ON transactions (client);
+            CREATE TABLE disputes (tx INTEGER PRIMARY KEY, state TEXT NOT NULL);
+            CREATE TABLE archive (tx INTEGER PRIMARY KEY, client INTEGER NOT NULL,
+                kind TEXT NOT NULL, amount TEXT NOT NULL, state TEXT);
+            CREATE INDEX archive_client ON archive (client);
+            CREATE TABLE events (seq INTEGER PRIMARY KEY, type TEXT NOT NULL,
+                client INTEGER NOT NULL, tx INTEGER NOT NULL, amount TEXT);
+            INSERT INTO accounts VALUES (1, '3', '2', '5', 0), (2, '0', '0', '0', 1);
+            INSERT INTO transactions VALUES (1, 1, 'deposit', '2'), (2, 1, 'deposit', '3');
+            INSERT INTO disputes VALUES (1, 'open');
+            INSERT INTO events VALUES (1, 'deposit', 1, 1, '2'), (2, 'deposit', 1, 2, '3'),
+                (3, 'dispute', 1, 1, NULL);
+            PRAGMA user_version = 2;",
+        )
+        .unwrap();
+
+        let mut accts = Accounts::new(SqliteContainer::init(conn).unwrap());
+        assert!(matches!(
+            accts.db.transaction(&TxID::numeric(1)),
+            Ok(Some(Record::Disputed(_)))
+        ));
+        assert_eq!(accts.db.transactions().count(), 2);
+        assert_eq!(accts.events().count(), 3);
+        assert_eq!(
+            accts.db.get_account(&ClientID(2)).unwrap().status(),
+            &Status::Frozen(Cause::new("chargeback", None))
+        );
+
+        let tx = "ref-7f0e5d6b".parse::<TxID>().unwrap();
+        accts
+            .process(TransactionData {
+                t_type: TransactionType::Deposit,
+                client: ClientID(1),
+                tx: tx.clone(),
+                amount: Some(Decimal::from(1)),
+                operator: None,
+                reason: None,
+            })
+            .unwrap();
+        assert!(matches!(
+            accts.db.transaction(&tx),
+            Ok(Some(Record::Deposit(_)))
+        ));
+        let version: i64 = accts
+            .db
+            .conn
+            .query_row("PRAGMA user_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn version_5_takes_administrative_operations() {
+        // laid out the way version 5 was, the kinds were payments only
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE accounts (client NOT NULL PRIMARY KEY, available TEXT NOT NULL,
+                held TEXT NOT NULL, total TEXT NOT NULL, locked INTEGER NOT NULL,
+                state TEXT NOT NULL DEFAULT 'active', reason TEXT, cause_tx);
+            CREATE TABLE transactions (tx NOT NULL PRIMARY KEY, client INTEGER NOT NULL,
+                kind TEXT NOT NULL CHECK (kind IN ('deposit', 'withdrawal')),
+                amount TEXT NOT NULL);
+            CREATE INDEX transactions_client ON transactions (client);
+            CREATE TABLE disputes (tx NOT NULL PRIMARY KEY REFERENCES transactions (tx),
+                state TEXT NOT NULL);
+            CREATE TABLE archive (tx NOT NULL PRIMARY KEY, client INTEGER NOT NULL,
+                kind TEXT NOT NULL CHECK (kind IN ('deposit', 'withdrawal')),
+                amount TEXT NOT NULL, state TEXT);
+            CREATE INDEX archive_client ON archive (client);
+            CREATE TABLE events (seq INTEGER PRIMARY KEY, type TEXT NOT NULL,
+                client INTEGER NOT NULL, tx NOT NULL, amount TEXT, operator TEXT, reason TEXT);
+            INSERT INTO accounts VALUES (1, '3', '2', '5', 0, 'active', NULL, NULL);
+            INSERT INTO transactions VALUES (2, 1, 'deposit', '2'), (3, 1, 'deposit', '3');
+            INSERT INTO disputes VALUES (2, 'open');
+            INSERT INTO archive VALUES (1, 1, 'withdrawal', '1', NULL);
+            PRAGMA user_version = 5;",
+        )
+        .unwrap();
+
+        let mut accts = Accounts::new(SqliteContainer::init(conn).unwrap()).with_admin(true);
+        assert!(matches!(
+            accts.db.transaction(&TxID::numeric(2)),
+            Ok(Some(Record::Disputed(_)))
+        ));
+        assert_eq!(accts.db.transactions().count(), 2);
+        assert!(matches!(
+            accts.db.archived(&TxID::numeric(1)),
+            Ok(Some(Record::Withdrawal(_)))
+        ));
+
+        accts
+            .process(TransactionData {
+                operator: Some("alice".to_string()),
+                reason: Some("fraud review".to_string()),
+                ..data(TransactionType::Freeze, 1, 4, None)
+            })
+            .unwrap();
+        assert!(matches!(
+            accts.db.transaction(&TxID::numeric(4)),
+            Ok(Some(Record::Ordered(_)))
+        ));
+        let indexes: i64 = accts
+            .db
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index'
+                 AND name IN ('transactions_client', 'archive_client')",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 2);
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+use csv::Writer;
+use serde::Serialize;
+
+use crate::failure::Failure;
+
+#[derive(Serialize)]
+struct Reject<'a> {
+    line: u64,
+    stage: &'static str,
+    error: &'static str,
+    reason: String,
+    raw: &'a str,
+}
+
+// Every rejected row with the reason it was rejected for.
+// Nothing is written when there is no file to write to.
+pub struct Rejects {
+    w: Option<Writer<File>>,
+}
+
+impl Rejects {
+    pub fn new(path: Option<&str>) -> csv::Result<Self> {
+        let w = match path {
+            Some(p) => Some(Writer::from_path(p)?),
+            None => None,
+        };
+        Ok(Self { w })
+    }
+
+    pub fn write(&mut self, line: u64, raw: &str, f: &Failure) -> csv::Result<()> {
+        let w = match self.w.as_mut() {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+
+        w.serialize(Reject {
+            line,
+            stage: f.stage(),
+            error: f.error(),
+            reason: f.reason(),
+            raw,
+        })
+    }
+
+    pub fn flush(&mut self) -> csv::Result<()> {
+        if let Some(w) = self.w.as_mut() {
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Keeps what the csv reader takes from the input
+// so a rejected row can be reported exactly as it came in.
+pub struct Tape<R> {
+    inner: R,
+    kept: Vec<u8>,
+    start: u64,
+}
+
+impl<R> Tape<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            kept: Vec::new(),
+            start: 0,
+        }
+    }
+
+    // the input between the byte offsets `from` and `to`
+    // without its line terminators, everything before `to` is let go
+    pub fn take(&mut self, from: u64, to: u64) -> String {
+        let from = (from.saturating_sub(self.start) as usize).min(self.kept.len());
+        let to = (to.saturating_sub(self.start) as usize).min(self.kept.len());
+
+        let row = String::from_utf8_lossy(&self.kept[from..to.max(from)])
+            .trim_matches(|c| c == '\r' || c == '\n')
+            .to_string();
+        self.kept.drain(..to);
+        self.start += to as u64;
+        row
+    }
+}
+
+impl<R: Read> Read for Tape<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.kept.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rows_are_taken_as_they_came_in() {
+        let input = "type, client\r\ndeposit, \"1\",  2, extra\r\n\nwithdrawal,1\n";
+        let mut r = csv::ReaderBuilder::default()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(Tape::new(input.as_bytes()));
+        r.byte_headers().unwrap();
+        let to = r.position().byte();
+        r.get_mut().take(0, to);
+
+        let mut record = csv::ByteRecord::new();
+        let mut rows = Vec::new();
+        loop {
+            let from = r.position().byte();
+            if !r.read_byte_record(&mut record).unwrap() {
+                break;
+            }
+            let to = r.position().byte();
+            rows.push(r.get_mut().take(from, to));
+        }
+
+        assert_eq!(rows, ["deposit, \"1\",  2, extra", "withdrawal,1"]);
+        assert!(r.get_ref().kept.is_empty());
+    }
+}